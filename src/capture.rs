@@ -0,0 +1,116 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use time::Duration;
+
+use crate::{
+    configuration::CaptureOptions,
+    data_model::{self, CollectionName},
+    handshake, logging, nss, output, pcap,
+    storage::Storage,
+};
+
+/// Writes the keys of the TLS handshakes found in a capture as an NSS key log,
+/// optionally embedding them into a pcapng copy of the capture.
+pub(crate) fn capture(options: &CaptureOptions, store: &mut dyn Storage) -> Result<()> {
+    let input_path = Path::new(&options.input);
+    let handshakes =
+        handshake::scan(pcap::Reader::open(input_path)?).with_context(|| format!("Failed to read capture {}", options.input))?;
+
+    let mut client_randoms = Vec::new();
+    let mut candidates = HashSet::new();
+    for handshake in &handshakes {
+        let sni = handshake
+            .sni
+            .as_deref()
+            .and_then(|s| data_model::parse_sni(s, handshake.server.ip(), handshake.server.port()).ok())
+            .unwrap_or_default();
+        // Keys may have been logged on the other side of midnight or with a skewed clock.
+        for days in -1..=1 {
+            candidates.insert(CollectionName {
+                sni: sni.clone(),
+                server_ip: handshake.server.ip(),
+                server_port: handshake.server.port(),
+                date: (handshake.timestamp + Duration::days(days)).date(),
+            });
+        }
+
+        client_randoms.push(handshake.client_random.clone());
+    }
+
+    client_randoms.sort();
+    client_randoms.dedup();
+    let records = if client_randoms.is_empty() {
+        Vec::new()
+    } else {
+        store.lookup(&client_randoms, &|n| candidates.contains(n))?
+    };
+
+    let mut key_log = HashMap::<&[u8], Vec<_>>::new();
+    for record in &records {
+        key_log
+            .entry(record.get_metadata().client_random.as_slice())
+            .or_default()
+            .push(record.as_ref());
+    }
+
+    let mut written = HashSet::new();
+    let mut missing = 0;
+    let mut mismatched = 0;
+    let mut content = String::new();
+    for handshake in &handshakes {
+        let candidates = key_log.get(handshake.client_random.as_slice()).map_or(&[][..], Vec::as_slice);
+        let record = candidates.iter().find(|r| {
+            handshake
+                .server_random
+                .as_ref()
+                .is_none_or(|s| *s == r.get_metadata().server_random)
+        });
+        match record {
+            Some(record) => {
+                if written.insert(record.get_metadata().server_random.as_slice()) {
+                    content.push_str(&nss::format_record(*record));
+                }
+            }
+            None => {
+                missing += 1;
+                if !candidates.is_empty() {
+                    mismatched += 1;
+                }
+            }
+        }
+    }
+
+    if let Some(keylog_output) = &options.keylog_output {
+        let mut output = output::open(keylog_output)?;
+        output
+            .write_all(content.as_bytes())
+            .and_then(|_| output.flush())
+            .context("Failed to write keys")?;
+    }
+
+    if let Some(pcapng_output) = &options.pcapng_output {
+        pcap::embed_secrets(input_path, Path::new(pcapng_output), content.as_bytes())?;
+    }
+
+    if missing != 0 {
+        logging::print_warning(&format!(
+            "No keys found for {} of {} TLS handshakes",
+            missing,
+            handshakes.len()
+        ));
+    }
+
+    if mismatched != 0 {
+        logging::print_warning(&format!(
+            "Ignored the keys of {} TLS handshakes whose server random does not match the capture",
+            mismatched
+        ));
+    }
+
+    Ok(())
+}
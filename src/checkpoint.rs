@@ -0,0 +1,165 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    input::{FileIdentity, Position},
+    output,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Entry {
+    identity: Option<FileIdentity>,
+    position: Position,
+}
+
+/// Per-file positions up to which the input has been durably stored.
+///
+/// Entries are keyed by path and are only used when the file identity (device and inode) still matches.
+/// The state is persisted as text lines in the following format:
+/// `<device> <inode> <offset> <line_num> <path>`
+pub(crate) struct State {
+    path: PathBuf,
+    entries: BTreeMap<String, Entry>,
+    is_dirty: bool,
+}
+
+impl State {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut state = Self {
+            path: path.to_path_buf(),
+            entries: BTreeMap::new(),
+            is_dirty: false,
+        };
+        let file = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open state file {}", path.display())),
+        };
+        for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let location = format!("{}:{}", path.display(), index + 1);
+            let line = line.with_context(|| format!("Failed to read state at {}", location))?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, entry) = parse_entry(&line).with_context(|| format!("Failed to parse state at {}", location))?;
+            state.entries.insert(name, entry);
+        }
+
+        Ok(state)
+    }
+
+    pub fn get(&self, name: &str, identity: Option<FileIdentity>) -> Option<Position> {
+        self.entries.get(name).filter(|e| e.identity == identity).map(|e| e.position)
+    }
+
+    /// Returns the identity and position saved for a file, whichever file has its name now.
+    pub fn entry(&self, name: &str) -> Option<(Option<FileIdentity>, Position)> {
+        self.entries.get(name).map(|e| (e.identity, e.position))
+    }
+
+    pub fn set(&mut self, name: &str, identity: Option<FileIdentity>, position: Position) {
+        let entry = Entry { identity, position };
+        if self.entries.get(name) != Some(&entry) {
+            self.entries.insert(String::from(name), entry);
+            self.is_dirty = true;
+        }
+    }
+
+    /// Atomically replaces the state file if any entry has changed since the last save.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.is_dirty {
+            return Ok(());
+        }
+
+        let mut temp_name = self.path.clone().into_os_string();
+        temp_name.push(".tmp");
+        let temp_path = PathBuf::from(temp_name);
+        let mut content = String::new();
+        for (name, entry) in &self.entries {
+            let (device, inode) = entry.identity.map(|i| (i.device, i.inode)).unwrap_or_default();
+            content.push_str(&format!(
+                "{} {} {} {} {}\n",
+                device, inode, entry.position.offset, entry.position.line_num, name
+            ));
+        }
+
+        let mut file =
+            std::fs::File::create(&temp_path).with_context(|| format!("Failed to create state file {}", temp_path.display()))?;
+        file.write_all(content.as_bytes())
+            .and_then(|_| file.sync_all())
+            .with_context(|| format!("Failed to write state file {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &self.path).with_context(|| format!("Failed to replace state file {}", self.path.display()))?;
+        let directory = match self.path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        output::sync_directory(directory)?;
+        self.is_dirty = false;
+        Ok(())
+    }
+}
+
+fn parse_entry(line: &str) -> Result<(String, Entry)> {
+    let mut parts = line.splitn(5, ' ');
+    let mut next_number = |name: &str| -> Result<u64> {
+        let part = parts.next().ok_or_else(|| anyhow!("Missing {}", name))?;
+        u64::from_str(part).with_context(|| format!("Invalid {} {}", name, part))
+    };
+    let device = next_number("device")?;
+    let inode = next_number("inode")?;
+    let offset = next_number("offset")?;
+    let line_num = next_number("line number")?;
+    let name = parts
+        .next()
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| anyhow!("Missing path"))?;
+    let identity = if device == 0 && inode == 0 {
+        None
+    } else {
+        Some(FileIdentity { device, inode })
+    };
+    Ok((
+        String::from(name),
+        Entry {
+            identity,
+            position: Position { offset, line_num },
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn state_roundtrips() {
+        let path = std::env::temp_dir().join(format!("sslkeylog-processor-state-{}", std::process::id()));
+        let identity = Some(FileIdentity {
+            device: 2049,
+            inode: 1234,
+        });
+        let position = Position {
+            offset: 4096,
+            line_num: 17,
+        };
+        let mut state = State::load(&path).unwrap();
+        state.set("/var/log/with space.log", identity, position);
+        state.save().unwrap();
+
+        let state = State::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(position), state.get("/var/log/with space.log", identity));
+        assert_eq!(
+            None,
+            state.get("/var/log/with space.log", Some(FileIdentity { device: 2049, inode: 1 }))
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context, Result};
+
+/// What to do with an input file that was processed and stored without errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SuccessAction {
+    Delete,
+    /// Moves the file into the directory.
+    Move(PathBuf),
+    /// Appends the suffix to the file name.
+    Rename(String),
+}
+
+/// Actions applied to the input files once processed, files are left in place if none is set.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Actions {
+    pub on_success: Option<SuccessAction>,
+    /// Directory receiving the files with lines that failed to be parsed or stored.
+    pub quarantine: Option<PathBuf>,
+}
+
+impl Actions {
+    pub fn is_empty(&self) -> bool {
+        self.on_success.is_none() && self.quarantine.is_none()
+    }
+
+    /// Applies the action for the outcome of a file, returning a description of what was done, if anything.
+    pub fn apply(&self, path: &Path, is_success: bool) -> Result<Option<String>> {
+        let action = match (is_success, &self.on_success, &self.quarantine) {
+            (true, Some(action), _) => action,
+            (false, _, Some(directory)) => return move_to(path, directory).map(Some),
+            _ => return Ok(None),
+        };
+        match action {
+            SuccessAction::Delete => {
+                std::fs::remove_file(path).with_context(|| format!("Failed to delete {}", path.display()))?;
+                Ok(Some(String::from("deleted")))
+            }
+            SuccessAction::Move(directory) => move_to(path, directory).map(Some),
+            SuccessAction::Rename(suffix) => {
+                let mut target = path.as_os_str().to_os_string();
+                target.push(suffix);
+                let target = PathBuf::from(target);
+                rename(path, &target)?;
+                Ok(Some(format!("renamed to {}", target.display())))
+            }
+        }
+    }
+}
+
+fn move_to(path: &Path, directory: &Path) -> Result<String> {
+    std::fs::create_dir_all(directory).with_context(|| format!("Failed to create directory {}", directory.display()))?;
+    let target = directory.join(path.file_name().unwrap_or_default());
+    rename(path, &target)?;
+    Ok(format!("moved to {}", target.display()))
+}
+
+/// Renames a file without replacing an existing one, copying it if the target is on another file system.
+pub(crate) fn rename(path: &Path, target: &Path) -> Result<()> {
+    let context = || format!("Failed to move {} to {}", path.display(), target.display());
+    ensure!(
+        !target.exists(),
+        "Failed to move {} to {}, which already exists",
+        path.display(),
+        target.display()
+    );
+    match std::fs::rename(path, target) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(path, target).with_context(context)?;
+            std::fs::remove_file(path).with_context(context)
+        }
+        result => result.with_context(context),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn actions_depend_on_the_outcome() {
+        let directory = std::env::temp_dir().join(format!("sslkeylog-processor-completion-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for name in ["ok.log", "failed.log", "existing.log"] {
+            std::fs::write(directory.join(name), b"").unwrap();
+        }
+        std::fs::create_dir_all(directory.join("quarantine")).unwrap();
+        std::fs::write(directory.join("quarantine/existing.log"), b"").unwrap();
+
+        let actions = Actions {
+            on_success: Some(SuccessAction::Rename(String::from(".done"))),
+            quarantine: Some(directory.join("quarantine")),
+        };
+        let renamed = actions.apply(&directory.join("ok.log"), true).unwrap();
+        let quarantined = actions.apply(&directory.join("failed.log"), false).unwrap();
+        let existing = actions.apply(&directory.join("existing.log"), false);
+        let is_moved = directory.join("ok.log.done").is_file() && directory.join("quarantine/failed.log").is_file();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(
+            Some(format!("renamed to {}", directory.join("ok.log.done").display())),
+            renamed
+        );
+        assert!(quarantined.unwrap().starts_with("moved to "));
+        assert!(existing.is_err());
+        assert!(is_moved);
+        assert_eq!(None, Actions::default().apply(&directory.join("ok.log"), true).unwrap());
+    }
+}
@@ -1,21 +1,102 @@
-use std::ffi::OsStr;
+use std::{
+    ffi::OsStr,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use regex::Regex;
 
-use crate::data_model::InputFormat;
+use crate::{
+    completion, data_model::InputFormat, discovery, input, journal, logging, output, processor::BatchPolicy,
+    storage::BinaryEncoding, syslog,
+};
 
 const PACKAGE_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug)]
 pub(crate) struct Configuration {
+    pub mode: Mode,
     pub files: Vec<String>,
-    pub options: mongodb::options::ClientOptions,
-    pub db_name: String,
+    /// Selects and orders the files found in directories and by glob patterns.
+    pub discovery: discovery::Options,
+    /// Applied to every file once processed.
+    pub completion: completion::Actions,
+    /// Missing in dry-run mode, which never stores anything.
+    pub storage: Option<StorageOptions>,
     pub filter: Option<Regex>,
     pub input_format: InputFormat,
+    pub nss_metadata: Option<String>,
+    pub journal_filter: journal::Filter,
+    /// Listens for syslog messages in place of reading files.
+    pub syslog: Option<syslog::Options>,
+    /// Watches a spool directory in place of reading files.
+    pub spool: Option<String>,
+    pub follow: bool,
+    pub flush_interval: Duration,
+    pub batching: BatchPolicy,
+    pub state_file: Option<String>,
+    /// Dead letter file receiving the lines that failed to be parsed.
+    pub reject_file: Option<String>,
+    pub debug_raw_lines: bool,
+    pub dry_run: bool,
+    pub report: Option<String>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub metrics_textfile: Option<String>,
+    pub log_level: logging::Level,
+    pub log_format: logging::Format,
 }
 
+#[derive(Debug)]
+pub(crate) enum StorageOptions {
+    Mongo {
+        options: Box<mongodb::options::ClientOptions>,
+        db_name: String,
+    },
+    Sqlite {
+        path: String,
+    },
+    Postgres {
+        connection_string: String,
+    },
+    Keylog {
+        directory: String,
+    },
+    Jsonl {
+        path: String,
+        encoding: BinaryEncoding,
+    },
+}
+
+#[derive(Debug)]
+pub(crate) enum Mode {
+    Process,
+    Lookup(LookupOptions),
+    Capture(CaptureOptions),
+}
+
+#[derive(Debug)]
+pub(crate) struct LookupOptions {
+    pub client_randoms: Vec<Vec<u8>>,
+    pub sni: Option<String>,
+    pub server_ip: Option<IpAddr>,
+    pub server_port: Option<u16>,
+    pub date: Option<time::Date>,
+    pub output: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct CaptureOptions {
+    pub input: String,
+    pub keylog_output: Option<String>,
+    pub pcapng_output: Option<String>,
+}
+
+const LOOKUP_COMMAND: &str = "lookup";
+const CAPTURE_COMMAND: &str = "capture";
+
 pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
 where
     Args: IntoIterator,
@@ -28,7 +109,7 @@ pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
         "c",
         "connection",
         "set connection string, start with @ to load from file",
-        "mongodb://.../database_name?params... | @file",
+        "mongodb://.../database_name?params... | sqlite://path | postgres://... | keylog://directory | jsonl://file|-[?encoding=hex|base64] | @file",
     );
     opts.optopt(
         "f",
@@ -40,7 +121,191 @@ pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
         "i",
         "input-format",
         "set input format (default: sslkeylog)",
-        "sslkeylog | ddgsyslog",
+        "sslkeylog | ddgsyslog | nss | journal | auto",
+    );
+    opts.optflag(
+        "",
+        "stdin",
+        "read lines from the standard input, same as passing - as a file name",
+    );
+    opts.optopt(
+        "",
+        "syslog-udp",
+        "receive syslog messages on UDP in place of files",
+        "ip:port",
+    );
+    opts.optopt(
+        "",
+        "syslog-tcp",
+        "receive syslog messages on TCP in place of files",
+        "ip:port",
+    );
+    opts.optmulti(
+        "",
+        "syslog-allow",
+        "accept syslog messages from the network, can be repeated (default: loopback only)",
+        "ip | ip/prefix",
+    );
+    opts.optmulti(
+        "",
+        "include",
+        "only process files found in directories that match the pattern, can be repeated",
+        "pattern",
+    );
+    opts.optmulti(
+        "",
+        "exclude",
+        "skip files and subdirectories found in directories that match the pattern, can be repeated",
+        "pattern",
+    );
+    opts.optopt(
+        "",
+        "sort",
+        "set order of the files found in directories and by glob patterns (default: name)",
+        "name | mtime",
+    );
+    opts.optflag(
+        "",
+        "delete-processed",
+        "delete files that were processed and stored without errors",
+    );
+    opts.optopt(
+        "",
+        "archive-dir",
+        "move files that were processed and stored without errors to directory",
+        "directory",
+    );
+    opts.optopt(
+        "",
+        "rename-suffix",
+        "rename files that were processed and stored without errors by appending suffix",
+        "suffix",
+    );
+    opts.optopt(
+        "",
+        "quarantine-dir",
+        "move files with lines that failed to be processed or stored to directory",
+        "directory",
+    );
+    opts.optopt(
+        "",
+        "spool",
+        "watch directory for new files in place of files, moving them to done/ or failed/ once processed",
+        "directory",
+    );
+    opts.optflag("F", "follow", "keep files open and process appended lines, handling rotation");
+    opts.optopt(
+        "",
+        "flush-interval",
+        "set maximum interval between flushes when following or receiving syslog, and between retries of spooled files (default: 10)",
+        "seconds",
+    );
+    opts.optopt(
+        "",
+        "batch-size",
+        "set number of records written at once (default: 1000)",
+        "records",
+    );
+    opts.optopt(
+        "",
+        "batch-max-age",
+        "write batches at most this long after their first record (default: unlimited)",
+        "seconds",
+    );
+    opts.optopt(
+        "",
+        "max-pending-records",
+        "write the least recently used batches beyond this number of pending records (default: 100000)",
+        "records",
+    );
+    opts.optopt(
+        "",
+        "max-pending-collections",
+        "write the least recently used batch beyond this number of pending batches (default: 1000)",
+        "batches",
+    );
+    opts.optflag("", "flush-per-file", "write all pending batches after every file");
+    opts.optopt("s", "state-file", "set file to store per-file positions for resuming", "file");
+    opts.optopt(
+        "",
+        "reject-file",
+        "append lines that fail to be parsed to file as JSON, with their location and reason",
+        "file",
+    );
+    opts.optflag("", "dry-run", "parse and filter without storing, printing per-file counts");
+    opts.optopt("", "report", "write the run statistics as JSON to file", "file | -");
+    opts.optopt(
+        "",
+        "metrics-listen",
+        "serve Prometheus metrics over HTTP at /metrics",
+        "ip:port",
+    );
+    opts.optopt(
+        "",
+        "metrics-textfile",
+        "write Prometheus metrics to file at exit, for the node exporter textfile collector",
+        "file.prom",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        &format!("set log level (default: info, or ${})", logging::LEVEL_VARIABLE),
+        "error | warn | info | debug | trace",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        &format!("set log format (default: text, or ${})", logging::FORMAT_VARIABLE),
+        "text | json",
+    );
+    opts.optflag(
+        "",
+        "debug-raw-lines",
+        "include raw lines and secrets in error messages, for debugging only",
+    );
+    opts.optopt("", "sni", "lookup: only search collections of the SNI", "host");
+    opts.optopt(
+        "",
+        "server",
+        "lookup: only search collections of the server",
+        "ip | ip:port | [ipv6]:port",
+    );
+    opts.optopt("", "date", "lookup: only search collections of the UTC date", "YYYY-MM-DD");
+    opts.optopt(
+        "",
+        "randoms-file",
+        "lookup: read additional client randoms from file, one per line",
+        "file",
+    );
+    opts.optopt(
+        "o",
+        "output",
+        "lookup, capture: set output key log file (default: standard output)",
+        "file | -",
+    );
+    opts.optopt(
+        "",
+        "pcapng",
+        "capture: write a copy of the capture with embedded decryption secrets",
+        "file",
+    );
+    opts.optmulti(
+        "",
+        "journal-identifier",
+        "journal: only process entries with the SYSLOG_IDENTIFIER, can be repeated",
+        "identifier",
+    );
+    opts.optmulti(
+        "",
+        "journal-unit",
+        "journal: only process entries with the _SYSTEMD_UNIT, can be repeated",
+        "unit",
+    );
+    opts.optopt(
+        "",
+        "nss-metadata",
+        "set connection metadata file for the nss input format",
+        "file",
     );
 
     let mut args = args.into_iter();
@@ -49,7 +314,7 @@ pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
         .map(|v| v.as_ref().to_string_lossy().to_string())
         .unwrap_or_else(|| String::from("program"));
     let args = args;
-    let matches = match opts.parse(args) {
+    let mut matches = match opts.parse(args) {
         Ok(m) => m,
         Err(e) => {
             print_usage(&program, &opts);
@@ -67,10 +332,31 @@ pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
         return Ok(None);
     }
 
-    let connection_string = matches.opt_str("c").ok_or_else(|| {
+    let log_level = matches
+        .opt_str("log-level")
+        .or_else(|| std::env::var(logging::LEVEL_VARIABLE).ok())
+        .map(|l| logging::Level::try_from(l.as_str()))
+        .transpose()?
+        .unwrap_or(logging::Level::Info);
+    let log_format = matches
+        .opt_str("log-format")
+        .or_else(|| std::env::var(logging::FORMAT_VARIABLE).ok())
+        .map(|f| logging::Format::try_from(f.as_str()))
+        .transpose()?
+        .unwrap_or(logging::Format::Text);
+
+    let dry_run = matches.opt_present("dry-run");
+    let report = matches.opt_str("report");
+    let metrics_listen = matches
+        .opt_str("metrics-listen")
+        .map(|a| SocketAddr::from_str(&a).context("Invalid metrics address"))
+        .transpose()?;
+    let metrics_textfile = matches.opt_str("metrics-textfile");
+    let connection_string = matches.opt_str("c");
+    if connection_string.is_none() && !dry_run {
         print_usage(&program, &opts);
-        anyhow!("Missing connection string")
-    })?;
+        bail!("Missing connection string");
+    }
 
     let filter = matches
         .opt_str("f")
@@ -78,28 +364,373 @@ pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
         .transpose()
         .context("Invalid filter")?;
 
+    let syslog = parse_syslog_options(&matches)?;
     let input_format = matches
         .opt_str("i")
         .map(|f| InputFormat::try_from(f.as_str()))
         .transpose()?
-        .unwrap_or(InputFormat::SslKeylog);
+        .unwrap_or(if syslog.is_some() {
+            InputFormat::DdgSyslog
+        } else {
+            InputFormat::SslKeylog
+        });
+    if syslog.is_some() && !matches!(input_format, InputFormat::DdgSyslog) {
+        bail!("Syslog messages only support the ddgsyslog input format");
+    }
 
-    let files = matches.free;
-    if files.is_empty() {
+    let nss_metadata = matches.opt_str("nss-metadata");
+    if matches!(input_format, InputFormat::Nss) && nss_metadata.is_none() {
         print_usage(&program, &opts);
-        bail!("Missing file names");
+        bail!("Missing NSS metadata file name");
+    }
+
+    let journal_filter = journal::Filter {
+        identifiers: matches.opt_strs("journal-identifier"),
+        units: matches.opt_strs("journal-unit"),
     };
 
-    let connection_string = if let Some(cs_name) = connection_string.strip_prefix('@') {
+    let follow = matches.opt_present("F");
+    let flush_interval = matches
+        .opt_str("flush-interval")
+        .map(|s| s.parse::<u64>().context("Invalid flush interval"))
+        .transpose()?
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(10));
+
+    let discovery = parse_discovery_options(&matches)?;
+    let completion = parse_completion_actions(&matches)?;
+    let spool = matches.opt_str("spool");
+    let batching = parse_batch_policy(&matches)?;
+    let state_file = matches.opt_str("s");
+    let reject_file = matches.opt_str("reject-file");
+    let debug_raw_lines = matches.opt_present("debug-raw-lines");
+    let read_stdin = matches.opt_present("stdin");
+    let mut files = std::mem::take(&mut matches.free);
+    let mode = if files.first().map(|f| f == LOOKUP_COMMAND).unwrap_or(false) {
+        files.remove(0);
+        let options = parse_lookup_options(&matches, std::mem::take(&mut files))?;
+        if options.client_randoms.is_empty() {
+            print_usage(&program, &opts);
+            bail!("Missing client randoms");
+        }
+
+        Mode::Lookup(options)
+    } else if files.first().map(|f| f == CAPTURE_COMMAND).unwrap_or(false) {
+        files.remove(0);
+        let input = match std::mem::take(&mut files)[..] {
+            [ref input] => input.clone(),
+            _ => {
+                print_usage(&program, &opts);
+                bail!("Expected a single capture file name");
+            }
+        };
+        let pcapng_output = matches.opt_str("pcapng");
+        let keylog_output = matches.opt_str("o").or_else(|| {
+            if pcapng_output.is_none() {
+                Some(String::from(output::STDOUT_PATH))
+            } else {
+                None
+            }
+        });
+        Mode::Capture(CaptureOptions {
+            input,
+            keylog_output,
+            pcapng_output,
+        })
+    } else {
+        if read_stdin && !files.iter().any(|f| f == input::STDIN_PATH) {
+            files.push(String::from(input::STDIN_PATH));
+        }
+
+        if syslog.is_some() {
+            if !files.is_empty() || spool.is_some() || follow || state_file.is_some() || dry_run {
+                bail!("Syslog messages cannot be combined with files, a spool directory, follow mode, a state file or a dry run");
+            }
+        } else if spool.is_some() {
+            if !files.is_empty() || follow || state_file.is_some() || dry_run {
+                bail!("Spool directory cannot be combined with files, follow mode, a state file or a dry run");
+            }
+        } else if files.is_empty() {
+            print_usage(&program, &opts);
+            bail!("Missing file names");
+        };
+
+        if follow && files.iter().any(|f| f == input::STDIN_PATH) {
+            bail!("Standard input cannot be followed");
+        }
+
+        if follow && matches!(input_format, InputFormat::Journal) {
+            bail!("Journal input cannot be followed, pipe journalctl --follow -o export to --stdin instead");
+        }
+
+        if follow && matches!(input_format, InputFormat::Auto) {
+            bail!("Auto input format is not supported in follow mode");
+        }
+
+        if dry_run && (follow || state_file.is_some()) {
+            bail!("Dry run is not supported in follow mode or with a state file");
+        }
+
+        if batching.flush_per_file && (syslog.is_some() || follow) {
+            bail!("Flushing per file is not supported with syslog messages or in follow mode");
+        }
+
+        if !completion.is_empty() && (syslog.is_some() || spool.is_some() || follow || dry_run) {
+            bail!("Processed file actions are not supported with syslog messages, a spool directory, follow mode or a dry run");
+        }
+
+        Mode::Process
+    };
+
+    if !matches!(mode, Mode::Process) {
+        for option in [
+            "filter",
+            "input-format",
+            "stdin",
+            "nss-metadata",
+            "journal-identifier",
+            "journal-unit",
+            "follow",
+            "flush-interval",
+            "state-file",
+            "debug-raw-lines",
+            "syslog-allow",
+            "dry-run",
+            "report",
+            "metrics-listen",
+            "metrics-textfile",
+            "syslog-udp",
+            "syslog-tcp",
+            "spool",
+            "reject-file",
+            "batch-size",
+            "batch-max-age",
+            "max-pending-records",
+            "max-pending-collections",
+            "flush-per-file",
+            "delete-processed",
+            "archive-dir",
+            "rename-suffix",
+            "quarantine-dir",
+            "include",
+            "exclude",
+            "sort",
+        ] {
+            if matches.opt_present(option) {
+                bail!("Option --{} is only supported when processing files", option);
+            }
+        }
+    }
+
+    let command = match mode {
+        Mode::Process => None,
+        Mode::Lookup(_) => Some(LOOKUP_COMMAND),
+        Mode::Capture(_) => Some(CAPTURE_COMMAND),
+    };
+    for (option, commands) in [
+        ("sni", &[LOOKUP_COMMAND][..]),
+        ("server", &[LOOKUP_COMMAND]),
+        ("date", &[LOOKUP_COMMAND]),
+        ("randoms-file", &[LOOKUP_COMMAND]),
+        ("output", &[LOOKUP_COMMAND, CAPTURE_COMMAND]),
+        ("pcapng", &[CAPTURE_COMMAND]),
+    ] {
+        if matches.opt_present(option) && !command.is_some_and(|c| commands.contains(&c)) {
+            bail!(
+                "Option --{} is only supported by the {} command",
+                option,
+                commands.join(" or ")
+            );
+        }
+    }
+
+    let storage = match connection_string {
+        Some(_) if dry_run => None,
+        Some(connection_string) => Some(parse_storage(&read_connection_string(connection_string)?)?),
+        None => None,
+    };
+
+    if report.as_deref() == Some(output::STDOUT_PATH)
+        && matches!(&storage, Some(StorageOptions::Jsonl { path, .. }) if path == output::STDOUT_PATH)
+    {
+        bail!("Report cannot be written to the standard output along with the JSON Lines output");
+    }
+
+    Ok(Some(Configuration {
+        mode,
+        files,
+        discovery,
+        completion,
+        storage,
+        filter,
+        input_format,
+        nss_metadata,
+        journal_filter,
+        syslog,
+        spool,
+        follow,
+        flush_interval,
+        batching,
+        state_file,
+        reject_file,
+        debug_raw_lines,
+        dry_run,
+        report,
+        metrics_listen,
+        metrics_textfile,
+        log_level,
+        log_format,
+    }))
+}
+
+fn parse_batch_policy(matches: &getopts::Matches) -> Result<BatchPolicy> {
+    let parse_count = |name: &str| {
+        matches
+            .opt_str(name)
+            .map(|c| {
+                c.parse::<usize>()
+                    .ok()
+                    .filter(|c| *c != 0)
+                    .ok_or_else(|| anyhow!("Invalid --{} {}", name, c))
+            })
+            .transpose()
+    };
+    let default = BatchPolicy::default();
+    Ok(BatchPolicy {
+        size: parse_count("batch-size")?.unwrap_or(default.size),
+        max_age: matches
+            .opt_str("batch-max-age")
+            .map(|s| s.parse::<u64>().context("Invalid batch maximum age"))
+            .transpose()?
+            .map(Duration::from_secs),
+        max_pending_records: parse_count("max-pending-records")?.unwrap_or(default.max_pending_records),
+        max_pending_collections: parse_count("max-pending-collections")?.unwrap_or(default.max_pending_collections),
+        flush_per_file: matches.opt_present("flush-per-file"),
+    })
+}
+
+fn parse_completion_actions(matches: &getopts::Matches) -> Result<completion::Actions> {
+    let mut actions = [
+        matches
+            .opt_present("delete-processed")
+            .then_some(completion::SuccessAction::Delete),
+        matches
+            .opt_str("archive-dir")
+            .map(|d| completion::SuccessAction::Move(PathBuf::from(d))),
+        matches.opt_str("rename-suffix").map(completion::SuccessAction::Rename),
+    ]
+    .into_iter()
+    .flatten();
+    let on_success = actions.next();
+    if actions.next().is_some() {
+        bail!("Options --delete-processed, --archive-dir and --rename-suffix are mutually exclusive");
+    }
+
+    if let Some(completion::SuccessAction::Rename(suffix)) = &on_success {
+        ensure!(
+            !suffix.is_empty() && !suffix.contains(std::path::is_separator),
+            "Invalid rename suffix {}",
+            suffix
+        );
+    }
+
+    Ok(completion::Actions {
+        on_success,
+        quarantine: matches.opt_str("quarantine-dir").map(PathBuf::from),
+    })
+}
+
+fn parse_discovery_options(matches: &getopts::Matches) -> Result<discovery::Options> {
+    let parse_patterns = |name: &str| {
+        matches
+            .opt_strs(name)
+            .iter()
+            .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid --{} pattern {}", name, p)))
+            .collect::<Result<Vec<_>>>()
+    };
+    Ok(discovery::Options {
+        include: parse_patterns("include")?,
+        exclude: parse_patterns("exclude")?,
+        sort: matches
+            .opt_str("sort")
+            .map(|s| discovery::SortOrder::try_from(s.as_str()))
+            .transpose()?
+            .unwrap_or_default(),
+    })
+}
+
+fn parse_syslog_options(matches: &getopts::Matches) -> Result<Option<syslog::Options>> {
+    let parse_address = |name: &str| {
+        matches
+            .opt_str(name)
+            .map(|a| SocketAddr::from_str(&a).with_context(|| format!("Invalid --{} address {}", name, a)))
+            .transpose()
+    };
+    let udp = parse_address("syslog-udp")?;
+    let tcp = parse_address("syslog-tcp")?;
+    if udp.is_none() && tcp.is_none() {
+        return Ok(None);
+    }
+
+    let allowed = matches
+        .opt_strs("syslog-allow")
+        .iter()
+        .map(|n| syslog::Network::from_str(n))
+        .collect::<Result<_>>()?;
+    Ok(Some(syslog::Options { udp, tcp, allowed }))
+}
+
+fn read_connection_string(connection_string: String) -> Result<String> {
+    Ok(if let Some(cs_name) = connection_string.strip_prefix('@') {
         let content = std::fs::read(cs_name).with_context(|| format!("Failed to read connection string from file {}", cs_name))?;
         let content =
             std::str::from_utf8(&content).with_context(|| format!("Broken connection string encoding in file {}", cs_name))?;
         content.strip_prefix('\u{FEFF}').unwrap_or(content).trim().to_owned()
     } else {
         connection_string
-    };
+    })
+}
+
+fn parse_storage(connection_string: &str) -> Result<StorageOptions> {
+    const SQLITE_PREFIX: &str = "sqlite://";
+    if let Some(path) = connection_string.strip_prefix(SQLITE_PREFIX) {
+        ensure!(!path.is_empty(), "Missing SQLite database path in connection string");
+        return Ok(StorageOptions::Sqlite {
+            path: String::from(path),
+        });
+    }
+
+    const KEYLOG_PREFIX: &str = "keylog://";
+    if let Some(directory) = connection_string.strip_prefix(KEYLOG_PREFIX) {
+        ensure!(!directory.is_empty(), "Missing key log directory in connection string");
+        return Ok(StorageOptions::Keylog {
+            directory: String::from(directory),
+        });
+    }
+
+    const JSONL_PREFIX: &str = "jsonl://";
+    if let Some(path) = connection_string.strip_prefix(JSONL_PREFIX) {
+        let (path, encoding) = match path.split_once('?') {
+            Some((path, query)) => match query.strip_prefix("encoding=") {
+                Some(encoding) => (path, BinaryEncoding::try_from(encoding)?),
+                None => bail!("Unsupported JSON Lines option {}", query),
+            },
+            None => (path, BinaryEncoding::Hex),
+        };
+        ensure!(!path.is_empty(), "Missing JSON Lines output file in connection string");
+        return Ok(StorageOptions::Jsonl {
+            path: String::from(path),
+            encoding,
+        });
+    }
 
-    let options = mongodb::options::ClientOptions::parse(&connection_string)
+    if connection_string.starts_with("postgres://") || connection_string.starts_with("postgresql://") {
+        return Ok(StorageOptions::Postgres {
+            connection_string: String::from(connection_string),
+        });
+    }
+
+    let options = mongodb::options::ClientOptions::parse(connection_string)
         .run()
         .context("Failed to parse connection string")?;
 
@@ -113,20 +744,69 @@ pub(crate) fn parse_args<Args>(args: Args) -> Result<Option<Configuration>>
         .and_then(|d| if d.is_empty() { None } else { Some(d) })
         .ok_or_else(|| anyhow!("Failed to parse database name from connection string"))?
         .to_owned();
-
-    Ok(Some(Configuration {
-        files,
-        options,
+    Ok(StorageOptions::Mongo {
+        options: Box::new(options),
         db_name,
-        filter,
-        input_format,
-    }))
+    })
+}
+
+fn parse_lookup_options(matches: &getopts::Matches, randoms: Vec<String>) -> Result<LookupOptions> {
+    let mut randoms = randoms;
+    if let Some(file_name) = matches.opt_str("randoms-file") {
+        let content = std::fs::read_to_string(&file_name)
+            .with_context(|| format!("Failed to read client randoms from file {}", file_name))?;
+        randoms.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(String::from),
+        );
+    }
+
+    let client_randoms = randoms
+        .iter()
+        .map(|r| {
+            hex::decode(r)
+                .ok()
+                .filter(|r| r.len() == 32)
+                .ok_or_else(|| anyhow!("Invalid client random {}", r))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let (server_ip, server_port) = match matches.opt_str("server") {
+        Some(server) => match SocketAddr::from_str(&server) {
+            Ok(a) => (Some(a.ip()), Some(a.port())),
+            Err(_) => (
+                Some(IpAddr::from_str(&server).with_context(|| format!("Invalid server {}", server))?),
+                None,
+            ),
+        },
+        None => (None, None),
+    };
+
+    const DATE_FORMAT: &[time::format_description::FormatItem] = time::macros::format_description!("[year]-[month]-[day]");
+    let date = matches
+        .opt_str("date")
+        .map(|d| time::Date::parse(&d, DATE_FORMAT).with_context(|| format!("Invalid date {}", d)))
+        .transpose()?;
+
+    Ok(LookupOptions {
+        client_randoms,
+        sni: matches.opt_str("sni").map(|s| s.trim_end_matches('.').to_ascii_lowercase()),
+        server_ip,
+        server_port,
+        date,
+        output: matches.opt_str("o").unwrap_or_else(|| String::from(output::STDOUT_PATH)),
+    })
 }
 
 fn print_usage(program: impl AsRef<str>, opts: &getopts::Options) {
     let brief = format!(
-        "Usage: {} file1 [file2...fileN] [options]\nVersion: {}",
+        "Usage: {0} file1|- [file2...fileN] [options]\n       {0} {1} random1 [random2...randomN] [options]\n       {0} {2} capture.pcap|capture.pcapng [options]\nVersion: {3}",
         program.as_ref(),
+        LOOKUP_COMMAND,
+        CAPTURE_COMMAND,
         PACKAGE_VERSION
     );
     print!("{}", opts.usage(&brief));
@@ -149,6 +829,227 @@ fn test() {
         .expect("Failed to get real arguments");
 
         assert_eq!(config.files, &["test", "test2"]);
-        assert_eq!(config.db_name, "keys");
+        match config.storage.unwrap() {
+            StorageOptions::Mongo { db_name, .. } => assert_eq!(db_name, "keys"),
+            _ => panic!("Expected MongoDB storage"),
+        }
+    }
+
+    #[test]
+    fn sqlite_connection_string_selects_sqlite() {
+        let config = parse_args(&["program", "test", "-c", "sqlite:///var/lib/keys.sqlite"])
+            .expect("Failed to parse arguments")
+            .expect("Failed to get real arguments");
+
+        match config.storage.unwrap() {
+            StorageOptions::Sqlite { path } => assert_eq!(path, "/var/lib/keys.sqlite"),
+            _ => panic!("Expected SQLite storage"),
+        }
+    }
+
+    #[test]
+    fn jsonl_connection_string_selects_encoding() {
+        let config = parse_args(&["program", "test", "-c", "jsonl://-?encoding=base64"])
+            .expect("Failed to parse arguments")
+            .expect("Failed to get real arguments");
+
+        match config.storage.unwrap() {
+            StorageOptions::Jsonl { path, encoding } => {
+                assert_eq!(path, "-");
+                assert_eq!(encoding, BinaryEncoding::Base64);
+            }
+            _ => panic!("Expected JSON Lines output"),
+        }
+    }
+
+    #[test]
+    fn jsonl_standard_output_excludes_report() {
+        assert!(parse_args(&["program", "test", "-c", "jsonl://-", "--report", "-"]).is_err());
+        assert!(parse_args(&["program", "test", "-c", "jsonl://-", "--report", "report.json"]).is_ok());
+    }
+
+    #[test]
+    fn lookup_parses_randoms_and_hints() {
+        let random = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        let config = parse_args(&[
+            "program",
+            "lookup",
+            random,
+            "--server",
+            "[2001:db8::1]:443",
+            "--date",
+            "2021-01-02",
+            "-c",
+            "mongodb://host/keys",
+        ])
+        .expect("Failed to parse arguments")
+        .expect("Failed to get real arguments");
+
+        match config.mode {
+            Mode::Lookup(options) => {
+                assert_eq!(options.client_randoms, vec![hex::decode(random).unwrap()]);
+                assert_eq!(options.server_port, Some(443));
+                assert_eq!(options.date.map(|d| d.day()), Some(2));
+            }
+            _ => panic!("Expected lookup mode"),
+        }
+    }
+
+    #[test]
+    fn command_options_are_rejected_elsewhere() {
+        let random = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+        for args in [
+            &["program", "test", "--sni", "example.com", "-c", "mongodb://host/keys"][..],
+            &["program", "test", "-o", "out.keylog", "-c", "mongodb://host/keys"],
+            &["program", "test", "--pcapng", "out.pcapng", "-c", "mongodb://host/keys"],
+            &["program", "lookup", random, "--state-file", "x", "-c", "mongodb://host/keys"],
+            &["program", "lookup", random, "-i", "nss", "-c", "mongodb://host/keys"],
+            &["program", "capture", "in.pcap", "--follow", "-c", "mongodb://host/keys"],
+            &[
+                "program",
+                "lookup",
+                random,
+                "--pcapng",
+                "out.pcapng",
+                "-c",
+                "mongodb://host/keys",
+            ],
+            &[
+                "program",
+                "capture",
+                "in.pcap",
+                "--date",
+                "2021-01-02",
+                "-c",
+                "mongodb://host/keys",
+            ],
+        ] {
+            assert!(parse_args(args).is_err(), "{:?}", args);
+        }
+
+        assert!(parse_args(&[
+            "program",
+            "capture",
+            "in.pcap",
+            "-o",
+            "out.keylog",
+            "-c",
+            "mongodb://host/keys"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn stdin_flag_adds_stdin_file() {
+        let config = parse_args(&["program", "--stdin", "-c", "mongodb://host/keys"])
+            .expect("Failed to parse arguments")
+            .expect("Failed to get real arguments");
+
+        assert_eq!(config.files, &["-"]);
+    }
+
+    #[test]
+    fn batch_policy_is_parsed() {
+        let config = parse_args(&[
+            "program",
+            "--batch-size",
+            "500",
+            "--batch-max-age",
+            "60",
+            "--max-pending-collections",
+            "10",
+            "-c",
+            "mongodb://host/keys",
+            "logs",
+        ])
+        .expect("Failed to parse arguments")
+        .expect("Failed to get real arguments");
+
+        assert_eq!(500, config.batching.size);
+        assert_eq!(Some(Duration::from_secs(60)), config.batching.max_age);
+        assert_eq!(100_000, config.batching.max_pending_records);
+        assert_eq!(10, config.batching.max_pending_collections);
+        assert!(parse_args(&["program", "--batch-size", "0", "-c", "mongodb://host/keys", "logs"]).is_err());
+    }
+
+    #[test]
+    fn completion_actions_are_exclusive() {
+        let config = parse_args(&[
+            "program",
+            "--archive-dir",
+            "archive",
+            "--quarantine-dir",
+            "bad",
+            "-c",
+            "mongodb://host/keys",
+            "logs",
+        ])
+        .expect("Failed to parse arguments")
+        .expect("Failed to get real arguments");
+
+        assert_eq!(
+            Some(completion::SuccessAction::Move(PathBuf::from("archive"))),
+            config.completion.on_success
+        );
+        assert_eq!(Some(PathBuf::from("bad")), config.completion.quarantine);
+        assert!(parse_args(&[
+            "program",
+            "--delete-processed",
+            "--rename-suffix",
+            ".done",
+            "-c",
+            "mongodb://host/keys",
+            "logs"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn spool_excludes_files() {
+        let config = parse_args(&["program", "--spool", "/var/spool/sslkeylog", "-c", "mongodb://host/keys"])
+            .expect("Failed to parse arguments")
+            .expect("Failed to get real arguments");
+
+        assert_eq!(Some("/var/spool/sslkeylog"), config.spool.as_deref());
+        assert!(parse_args(&["program", "--spool", "spool", "-c", "mongodb://host/keys", "file"]).is_err());
+    }
+
+    #[test]
+    fn discovery_options_are_parsed() {
+        let config = parse_args(&[
+            "program",
+            "--include",
+            "*.log",
+            "--exclude",
+            "old",
+            "--sort",
+            "mtime",
+            "-c",
+            "mongodb://host/keys",
+            "logs",
+        ])
+        .expect("Failed to parse arguments")
+        .expect("Failed to get real arguments");
+
+        assert_eq!(1, config.discovery.include.len());
+        assert_eq!("old", config.discovery.exclude[0].as_str());
+        assert_eq!(discovery::SortOrder::Mtime, config.discovery.sort);
+        assert!(parse_args(&["program", "--sort", "size", "-c", "mongodb://host/keys", "logs"]).is_err());
+    }
+
+    #[test]
+    fn capture_defaults_to_standard_output_without_pcapng() {
+        let config = parse_args(&["program", "capture", "in.pcap", "-c", "mongodb://host/keys"])
+            .expect("Failed to parse arguments")
+            .expect("Failed to get real arguments");
+
+        match config.mode {
+            Mode::Capture(options) => {
+                assert_eq!(options.input, "in.pcap");
+                assert_eq!(options.keylog_output.as_deref(), Some("-"));
+                assert_eq!(options.pcapng_output, None);
+            }
+            _ => panic!("Expected capture mode"),
+        }
     }
 }
@@ -1,12 +1,15 @@
-use std::{convert::TryFrom, net::IpAddr, str::FromStr};
+use std::{convert::TryFrom, fmt, net::IpAddr, str::FromStr};
 
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use mongodb::bson::{self, doc};
 use regex::Regex;
-use time::{format_description, OffsetDateTime};
+use time::{format_description, format_description::FormatItem, macros::format_description, Date, OffsetDateTime};
 use url::{self, Host, Url};
 
-use crate::{logging, to_bson::ToBson};
+use crate::{
+    logging, redaction, stats,
+    to_bson::{FromBson, ToBson},
+};
 
 pub(crate) trait BsonSerializable {
     fn serialize(&self, document: &mut bson::Document);
@@ -16,6 +19,11 @@ pub(crate) trait BsonSerializable {
 pub(crate) enum InputFormat {
     SslKeylog,
     DdgSyslog,
+    Nss,
+    /// Journal export format, with DDG syslog messages.
+    Journal,
+    /// Detected per file among the [`InputLine`] formats, see [`InputLine::is_match`].
+    Auto,
 }
 
 pub(crate) enum InputLine<'a> {
@@ -23,10 +31,53 @@ pub(crate) enum InputLine<'a> {
     DdgSyslog(&'a str),
 }
 
+impl InputLine<'_> {
+    /// Tells whether the line has the layout of a TLS pre-1.3 or 1.3 record, without validating its fields.
+    pub fn is_match(&self) -> bool {
+        match self {
+            InputLine::SslKeylog(s) => TLS_PRE13_SSLKEYLOG_REGEX.is_match(s) || TLS13_SSLKEYLOG_REGEX.is_match(s),
+            InputLine::DdgSyslog(s) => TLS_PRE13_DDG_SYSLOG_REGEX.is_match(s) || TLS13_DDG_SYSLOG_REGEX.is_match(s),
+        }
+    }
+}
+
+lazy_static! {
+    static ref TLS_PRE13_SSLKEYLOG_REGEX: Regex = Regex::new(
+        r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z) (\S+?):(?:\d{1,5}) (\S+?):(\d{1,5}) (\S*) (?:[0-9a-fA-F]{1,4}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,})$"
+    )
+    .expect("Failed to parse TLS pre-1.3 record sslkeylog filter regex");
+    static ref TLS_PRE13_DDG_SYSLOG_REGEX: Regex = Regex::new(
+        r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z?) (\S*) (\S+?) (\S+?) (?:\d{1,5}) (\d{1,5}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) \- \- \- \- (?:[0-9a-fA-F]{1,4}) ([0-9a-fA-F]{16,})$"
+    )
+    .expect("Failed to parse TLS pre-1.3 record DDG syslog filter regex");
+    static ref TLS13_SSLKEYLOG_REGEX: Regex = Regex::new(
+        r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z) (\S+?):(?:\d{1,5}) (\S+?):(\d{1,5}) (\S*) (?:[0-9a-fA-F]{1,4}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,})$"
+    )
+    .expect("Failed to parse TLS 1.3 sslkeylog record filter regex");
+    static ref TLS13_DDG_SYSLOG_REGEX: Regex = Regex::new(
+        r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z?) (\S*) (\S+?) (\S+?) (?:\d{1,5}) (\d{1,5}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) (?:[0-9a-fA-F]{1,4}) -$"
+    )
+    .expect("Failed to parse TLS 1.3 DDG syslog record filter regex");
+}
+
 pub(crate) trait TlsRecord: BsonSerializable {
     fn get_metadata(&self) -> &RecordMetadata;
+    fn get_secrets(&self) -> TlsSecrets<'_>;
+}
+
+pub(crate) enum TlsSecrets<'a> {
+    Pre13 {
+        premaster: &'a [u8],
+    },
+    Tls13 {
+        server_handshake: &'a [u8],
+        client_handshake: &'a [u8],
+        server_0: &'a [u8],
+        client_0: &'a [u8],
+    },
 }
 
+#[derive(Clone)]
 pub(crate) struct RecordMetadata {
     pub timestamp: OffsetDateTime,
     pub client_ip: IpAddr,
@@ -62,6 +113,12 @@ impl TlsRecord for TlsPre13Record {
     fn get_metadata(&self) -> &RecordMetadata {
         &self.metadata
     }
+
+    fn get_secrets(&self) -> TlsSecrets<'_> {
+        TlsSecrets::Pre13 {
+            premaster: &self.premaster,
+        }
+    }
 }
 
 impl TryFrom<&InputLine<'_>> for TlsPre13Record {
@@ -76,15 +133,9 @@ fn try_from(value: &InputLine<'_>) -> Result<Self, Self::Error> {
 }
 
 fn tls_pre13_from_sslkeylog(value: &str) -> Result<TlsPre13Record, anyhow::Error> {
-    const FILTER_REGEX_PATTERN: &str = r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z) (\S+?):(?:\d{1,5}) (\S+?):(\d{1,5}) (\S*) (?:[0-9a-fA-F]{1,4}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,})$";
-    lazy_static! {
-        static ref FILTER_REGEX: Regex =
-            Regex::new(FILTER_REGEX_PATTERN).expect("Failed to parse TLS pre-1.3 record sslkeylog filter regex");
-    }
-
-    let captures = FILTER_REGEX
+    let captures = TLS_PRE13_SSLKEYLOG_REGEX
         .captures(value)
-        .with_context(|| format!("Invalid TLS pre-1.3 sslkeylog line {}", value))?;
+        .with_context(|| format!("Invalid TLS pre-1.3 sslkeylog line {}", redaction::line(value)))?;
     let metadata = RecordMetadata::try_from(&RecordMetadataSource {
         timestamp: &captures[1],
         client_ip: &captures[2],
@@ -100,15 +151,9 @@ fn tls_pre13_from_sslkeylog(value: &str) -> Result<TlsPre13Record, anyhow::Error
 }
 
 fn tls_pre13_from_ddg_syslog(value: &str) -> Result<TlsPre13Record, anyhow::Error> {
-    const FILTER_REGEX_PATTERN: &str = r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z?) (\S*) (\S+?) (\S+?) (?:\d{1,5}) (\d{1,5}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) \- \- \- \- (?:[0-9a-fA-F]{1,4}) ([0-9a-fA-F]{16,})$";
-    lazy_static! {
-        static ref FILTER_REGEX: Regex =
-            Regex::new(FILTER_REGEX_PATTERN).expect("Failed to parse TLS pre-1.3 record DDG syslog filter regex");
-    }
-
-    let captures = FILTER_REGEX
+    let captures = TLS_PRE13_DDG_SYSLOG_REGEX
         .captures(value)
-        .with_context(|| format!("Invalid TLS pre-1.3 DDG syslog line {}", value))?;
+        .with_context(|| format!("Invalid TLS pre-1.3 DDG syslog line {}", redaction::line(value)))?;
     let metadata = RecordMetadata::try_from(&RecordMetadataSource {
         timestamp: &captures[1],
         sni: &captures[2],
@@ -145,6 +190,15 @@ impl TlsRecord for Tls13Record {
     fn get_metadata(&self) -> &RecordMetadata {
         &self.metadata
     }
+
+    fn get_secrets(&self) -> TlsSecrets<'_> {
+        TlsSecrets::Tls13 {
+            server_handshake: &self.server_handshake,
+            client_handshake: &self.client_handshake,
+            server_0: &self.server_0,
+            client_0: &self.client_0,
+        }
+    }
 }
 
 impl<'a> From<&'a Tls13Record> for &'a RecordMetadata {
@@ -165,15 +219,9 @@ fn try_from(value: &InputLine<'_>) -> Result<Self, Self::Error> {
 }
 
 fn tls13_from_sslkeylog(value: &str) -> Result<Tls13Record, anyhow::Error> {
-    const FILTER_REGEX_PATTERN: &str = r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z) (\S+?):(?:\d{1,5}) (\S+?):(\d{1,5}) (\S*) (?:[0-9a-fA-F]{1,4}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,})$";
-    lazy_static! {
-        static ref FILTER_REGEX: Regex =
-            Regex::new(FILTER_REGEX_PATTERN).expect("Failed to parse TLS 1.3 sslkeylog record filter regex");
-    }
-
-    let captures = FILTER_REGEX
+    let captures = TLS13_SSLKEYLOG_REGEX
         .captures(value)
-        .with_context(|| format!("Invalid TLS 1.3 sslkeylog line {}", value))?;
+        .with_context(|| format!("Invalid TLS 1.3 sslkeylog line {}", redaction::line(value)))?;
     let metadata = RecordMetadata::try_from(&RecordMetadataSource {
         timestamp: &captures[1],
         client_ip: &captures[2],
@@ -198,15 +246,9 @@ fn tls13_from_sslkeylog(value: &str) -> Result<Tls13Record, anyhow::Error> {
 }
 
 fn tls13_from_ddg_syslog(value: &str) -> Result<Tls13Record, anyhow::Error> {
-    const FILTER_REGEX_PATTERN: &str = r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z?) (\S*) (\S+?) (\S+?) (?:\d{1,5}) (\d{1,5}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) ([0-9a-fA-F]{16,}) (?:[0-9a-fA-F]{1,4}) -$";
-    lazy_static! {
-        static ref FILTER_REGEX: Regex =
-            Regex::new(FILTER_REGEX_PATTERN).expect("Failed to parse TLS 1.3 DDG syslog record filter regex");
-    }
-
-    let captures = FILTER_REGEX
+    let captures = TLS13_DDG_SYSLOG_REGEX
         .captures(value)
-        .with_context(|| format!("Invalid TLS 1.3 DDG syslog line {}", value))?;
+        .with_context(|| format!("Invalid TLS 1.3 DDG syslog line {}", redaction::line(value)))?;
     let metadata = RecordMetadata::try_from(&RecordMetadataSource {
         timestamp: &captures[1],
         sni: &captures[2],
@@ -230,6 +272,98 @@ fn tls13_from_ddg_syslog(value: &str) -> Result<Tls13Record, anyhow::Error> {
     })
 }
 
+/// Converts a stored document back into a record, taking the endpoint metadata from its collection name.
+pub(crate) fn record_from_document(document: &bson::Document, collection: &CollectionName) -> Result<Box<dyn TlsRecord>> {
+    let get = |key: &str| document.get(key).ok_or_else(|| anyhow!("Missing field {}", key));
+    let get_bytes = |key: &str| get(key).and_then(|v| Vec::<u8>::from_bson(v).with_context(|| format!("Invalid field {}", key)));
+    let timestamp = match get("t")? {
+        bson::Bson::DateTime(t) => OffsetDateTime::from(*t),
+        _ => bail!("Invalid field t"),
+    };
+    let metadata = RecordMetadata {
+        timestamp,
+        client_ip: IpAddr::from_bson(get("i")?).context("Invalid field i")?,
+        server_ip: collection.server_ip,
+        server_port: collection.server_port,
+        sni: collection.sni.clone(),
+        server_random: get_bytes("_id")?,
+        client_random: get_bytes("r")?,
+    };
+    Ok(if document.contains_key("k") {
+        Box::from(TlsPre13Record {
+            metadata,
+            premaster: get_bytes("k")?,
+        })
+    } else {
+        Box::from(Tls13Record {
+            metadata,
+            server_handshake: get_bytes("h")?,
+            client_handshake: get_bytes("f")?,
+            server_0: get_bytes("z")?,
+            client_0: get_bytes("s")?,
+        })
+    })
+}
+
+/// Name of the collection holding the keys of a single server endpoint for a single (UTC) day,
+/// formatted as `<sni>@<server_ip>:<server_port>_<year><month><day>`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CollectionName {
+    pub sni: String,
+    pub server_ip: IpAddr,
+    pub server_port: u16,
+    pub date: Date,
+}
+
+const COLLECTION_DATE_FORMAT: &[FormatItem] = format_description!("[year][month][day]");
+
+impl CollectionName {
+    pub fn new(metadata: &RecordMetadata, timestamp: OffsetDateTime) -> Self {
+        Self {
+            sni: metadata.sni.clone(),
+            server_ip: metadata.server_ip,
+            server_port: metadata.server_port,
+            date: timestamp.date(),
+        }
+    }
+}
+
+impl fmt::Display for CollectionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{}:{}_{}",
+            self.sni,
+            self.server_ip,
+            self.server_port,
+            self.date.format(COLLECTION_DATE_FORMAT).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+impl FromStr for CollectionName {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (endpoint, date) = s
+            .rsplit_once('_')
+            .ok_or_else(|| anyhow!("Missing date in collection name {}", s))?;
+        let (sni, endpoint) = endpoint
+            .split_once('@')
+            .ok_or_else(|| anyhow!("Missing SNI in collection name {}", s))?;
+        let (server_ip, server_port) = endpoint
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Missing server port in collection name {}", s))?;
+        Ok(Self {
+            sni: String::from(sni),
+            server_ip: IpAddr::from_str(server_ip)
+                .with_context(|| format!("Invalid server IP address in collection name {}", s))?,
+            server_port: u16::from_str(server_port).with_context(|| format!("Invalid server port in collection name {}", s))?,
+            date: Date::parse(date, COLLECTION_DATE_FORMAT).with_context(|| format!("Invalid date in collection name {}", s))?,
+        })
+    }
+}
+
 pub(crate) fn get_index_model() -> Vec<bson::Document> {
     vec![
         doc! {
@@ -243,7 +377,7 @@ pub(crate) fn get_index_model() -> Vec<bson::Document> {
     ]
 }
 
-struct RecordMetadataSource<'a> {
+pub(crate) struct RecordMetadataSource<'a> {
     pub timestamp: &'a str,
     pub client_ip: &'a str,
     pub server_ip: &'a str,
@@ -273,7 +407,8 @@ fn try_from(value: &RecordMetadataSource) -> Result<Self, anyhow::Error> {
         let sni = match sni {
             Ok(v) => v,
             Err(e) => {
-                logging::print_warning(&e);
+                logging::log_error(logging::Level::Warn, &e, &[]);
+                stats::count_sni_warning();
                 String::new()
             }
         };
@@ -290,7 +425,7 @@ fn try_from(value: &RecordMetadataSource) -> Result<Self, anyhow::Error> {
     }
 }
 
-fn parse_sni(sni: &str, server_ip: IpAddr, server_port: u16) -> Result<String> {
+pub(crate) fn parse_sni(sni: &str, server_ip: IpAddr, server_port: u16) -> Result<String> {
     if sni.is_empty() {
         return Ok(String::new());
     }
@@ -337,13 +472,91 @@ fn try_from(s: &str) -> std::result::Result<Self, Self::Error> {
         match s.to_ascii_lowercase().as_str() {
             "sslkeylog" => Ok(Self::SslKeylog),
             "ddgsyslog" => Ok(Self::DdgSyslog),
+            "nss" => Ok(Self::Nss),
+            "journal" => Ok(Self::Journal),
+            "auto" => Ok(Self::Auto),
             _ => Err(anyhow!("Invalid input format")),
         }
     }
 }
 
-fn tls_secret_try_from(value: &str, kind: &str) -> Result<Vec<u8>, anyhow::Error> {
-    hex::decode(value).with_context(|| format!("Invalid TLS {} secret {}", kind, value))
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SslKeylog => "sslkeylog",
+            Self::DdgSyslog => "ddgsyslog",
+            Self::Nss => "nss",
+            Self::Journal => "journal",
+            Self::Auto => "auto",
+        })
+    }
+}
+
+/// Detects the format of sample lines among the [`InputLine`] formats.
+///
+/// Exactly one format must match some of the lines; lines matching no format are tolerated.
+pub(crate) fn detect_input_format<'a>(lines: impl IntoIterator<Item = &'a str>) -> Result<InputFormat> {
+    const FORMATS: [InputFormat; 2] = [InputFormat::SslKeylog, InputFormat::DdgSyslog];
+    let mut counts = [0; FORMATS.len()];
+    let mut line_count = 0;
+    for line in lines {
+        line_count += 1;
+        for (format, count) in FORMATS.iter().zip(&mut counts) {
+            let input_line = match format {
+                InputFormat::SslKeylog => InputLine::SslKeylog(line),
+                _ => InputLine::DdgSyslog(line),
+            };
+            if input_line.is_match() {
+                *count += 1;
+            }
+        }
+    }
+
+    let matches: Vec<_> = FORMATS
+        .iter()
+        .zip(counts)
+        .filter(|(_, count)| *count != 0)
+        .map(|(format, count)| (*format, count))
+        .collect();
+    match matches.as_slice() {
+        [(format, _)] => Ok(*format),
+        [] => bail!(
+            "Unrecognized input format, none of the first {} lines matches {}",
+            line_count,
+            FORMATS.map(|f| f.to_string()).join(" or ")
+        ),
+        _ => bail!(
+            "Ambiguous input format, lines match {}",
+            matches
+                .iter()
+                .map(|(format, count)| format!("{} ({} of {})", format, count, line_count))
+                .collect::<Vec<_>>()
+                .join(" and ")
+        ),
+    }
+}
+
+/// Tells whether a DDG syslog message starts with its own timestamp.
+pub(crate) fn starts_with_timestamp(message: &str) -> bool {
+    lazy_static! {
+        static ref TIMESTAMP_REGEX: Regex =
+            Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}").expect("Failed to parse DDG syslog timestamp regex");
+    }
+
+    TIMESTAMP_REGEX.is_match(message)
+}
+
+/// Prefixes a DDG syslog message with the timestamp of its envelope, e.g. a journal entry or a syslog header.
+pub(crate) fn prefix_timestamp(message: &str, timestamp: OffsetDateTime) -> String {
+    let timestamp = timestamp
+        .to_offset(time::UtcOffset::UTC)
+        .format(&format_description::well_known::Rfc3339)
+        .unwrap_or_default();
+    format!("{} {}", timestamp, message)
+}
+
+pub(crate) fn tls_secret_try_from(value: &str, kind: &str) -> Result<Vec<u8>, anyhow::Error> {
+    hex::decode(value).with_context(|| format!("Invalid TLS {} secret {}", kind, redaction::secret(kind, value)))
 }
 
 #[cfg(test)]
@@ -354,6 +567,37 @@ fn parse_sni_test(sni: &str, server_ip: &str, server_port: u16) -> String {
         parse_sni(sni, IpAddr::from_str(server_ip).unwrap(), server_port).unwrap()
     }
 
+    #[test]
+    fn detect_input_format_requires_a_single_match() {
+        let sslkeylog = format!(
+            "2021-01-02T03:04:05Z 10.0.0.1:50000 10.0.0.2:443 example.com 303 {} {} {}",
+            "11".repeat(32),
+            "22".repeat(32),
+            "33".repeat(48)
+        );
+        let ddg_syslog = format!(
+            "2021-01-02T03:04:05Z example.com 10.0.0.1 10.0.0.2 50000 443 {} {} - - - - 303 {}",
+            "11".repeat(32),
+            "22".repeat(32),
+            "33".repeat(48)
+        );
+
+        assert!(matches!(
+            detect_input_format(["# header", &ddg_syslog]),
+            Ok(InputFormat::DdgSyslog)
+        ));
+        assert!(matches!(
+            detect_input_format([sslkeylog.as_str()]),
+            Ok(InputFormat::SslKeylog)
+        ));
+        let err = detect_input_format(["garbage"]).unwrap_err().to_string();
+        assert!(err.starts_with("Unrecognized input format"), "{}", err);
+        let err = detect_input_format([sslkeylog.as_str(), &ddg_syslog])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("sslkeylog (1 of 2) and ddgsyslog (1 of 2)"), "{}", err);
+    }
+
     #[test]
     fn parse_sni_normalizes_domain() {
         assert_eq!(
@@ -381,6 +625,20 @@ fn parse_sni_fails_on_invalid_port() {
         parse_sni_test("just-a-host.com:1193", "127.0.0.1", 80);
     }
 
+    #[test]
+    fn collection_name_roundtrips() {
+        for name in ["some.host@10.0.0.1:443_20210102", "@2001:db8::1:8443_20211231"] {
+            assert_eq!(name, CollectionName::from_str(name).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn parse_errors_do_not_disclose_lines() {
+        let line = "2021-01-02T03:04:05Z 10.0.0.1:50000 10.0.0.2:443 example.com 303 broken";
+        let error = format!("{:?}", TlsPre13Record::try_from(&InputLine::SslKeylog(line)).err().unwrap());
+        assert!(!error.contains("broken"));
+    }
+
     #[test]
     fn parse_sni_succeeds_on_invalid_implicit_port() {
         assert_eq!("just-a-host.com", parse_sni_test("just-a-host.com", "127.0.0.1", 80,));
@@ -0,0 +1,181 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use glob::Pattern;
+
+use crate::input;
+
+/// Selects the files found in directories, files that are named explicitly or match a glob pattern are always kept.
+#[derive(Debug, Default)]
+pub(crate) struct Options {
+    /// Patterns of which at least one must match, all files are included if empty.
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub sort: SortOrder,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum SortOrder {
+    #[default]
+    Name,
+    /// Oldest modification time first, e.g. to process rotated files before the current one.
+    Mtime,
+}
+
+impl TryFrom<&str> for SortOrder {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "name" => Ok(SortOrder::Name),
+            "mtime" => Ok(SortOrder::Mtime),
+            _ => bail!("Unsupported sort order {}", value),
+        }
+    }
+}
+
+impl Options {
+    /// Tells whether a discovered path is selected, matching the patterns against both its name and its relative path.
+    fn accepts(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let matches = |pattern: &Pattern| {
+            pattern.matches_path(relative_path) || relative_path.file_name().is_some_and(|n| pattern.matches_path(Path::new(n)))
+        };
+        if self.exclude.iter().any(matches) {
+            return false;
+        }
+
+        is_dir || self.include.is_empty() || self.include.iter().any(matches)
+    }
+}
+
+/// Expands the file arguments, recursing into directories and expanding glob patterns on every OS.
+///
+/// The files found for every argument are sorted, while the arguments keep their order;
+/// a file found several times is only returned once.
+pub(crate) fn expand(arguments: &[String], options: &Options) -> Result<Vec<String>> {
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for argument in arguments {
+        let path = Path::new(argument);
+        let found = if argument == input::STDIN_PATH || path.is_file() {
+            vec![path.to_path_buf()]
+        } else if path.is_dir() {
+            sort(walk(path, options)?, options.sort)?
+        } else if is_pattern(argument) {
+            let mut found = Vec::new();
+            for entry in glob::glob(argument).with_context(|| format!("Invalid file pattern {}", argument))? {
+                let entry = entry.context("Failed to expand file pattern")?;
+                if entry.is_dir() {
+                    found.extend(walk(&entry, options)?);
+                } else {
+                    found.push(entry);
+                }
+            }
+
+            ensure!(!found.is_empty(), "No files match {}", argument);
+            sort(found, options.sort)?
+        } else {
+            // Missing files are reported when they are opened, like any other file
+            vec![path.to_path_buf()]
+        };
+
+        for path in found {
+            if seen.insert(path.clone()) {
+                files.push(path.display().to_string());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn is_pattern(argument: &str) -> bool {
+    argument.contains(['*', '?', '['])
+}
+
+/// Lists the accepted files below a directory, without following symbolic links to directories.
+fn walk(root: &Path, options: &Options) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let entries = std::fs::read_dir(&directory).with_context(|| format!("Failed to read directory {}", directory.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to read directory {}", directory.display()))?;
+            let path = entry.path();
+            let relative_path = path.strip_prefix(root).unwrap_or(&path);
+            let file_type = entry
+                .file_type()
+                .with_context(|| format!("Failed to get type of {}", path.display()))?;
+            if file_type.is_dir() {
+                if options.accepts(relative_path, true) {
+                    directories.push(path);
+                }
+            } else if (file_type.is_file() || path.is_file()) && options.accepts(relative_path, false) {
+                files.push(path);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn sort(mut files: Vec<PathBuf>, order: SortOrder) -> Result<Vec<PathBuf>> {
+    match order {
+        SortOrder::Name => files.sort(),
+        SortOrder::Mtime => {
+            let mut timed = files
+                .into_iter()
+                .map(|f| {
+                    let modified = std::fs::metadata(&f)
+                        .and_then(|m| m.modified())
+                        .with_context(|| format!("Failed to get modification time of {}", f.display()))?;
+                    Ok((modified, f))
+                })
+                .collect::<Result<Vec<(SystemTime, PathBuf)>>>()?;
+            timed.sort();
+            files = timed.into_iter().map(|(_, f)| f).collect();
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn directories_and_patterns_are_expanded() {
+        let root = std::env::temp_dir().join(format!("sslkeylog-processor-discovery-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("nested/archive")).unwrap();
+        for name in ["b.log", "a.log.gz", "notes.txt", "nested/c.log", "nested/archive/d.log"] {
+            std::fs::write(root.join(name), b"").unwrap();
+        }
+
+        let options = Options {
+            include: vec![Pattern::new("*.log*").unwrap()],
+            exclude: vec![Pattern::new("archive").unwrap()],
+            sort: SortOrder::Name,
+        };
+        let root_name = root.display().to_string();
+        let arguments = [
+            root.join("b.log").display().to_string(),
+            root_name.clone(),
+            format!("{}/*.txt", root_name),
+        ];
+        let files = expand(&arguments, &options);
+        let missing = expand(&[format!("{}/*.csv", root_name)], &options);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let expected: Vec<_> = ["b.log", "a.log.gz", "nested/c.log", "notes.txt"]
+            .iter()
+            .map(|n| root.join(n).display().to_string())
+            .collect();
+        assert_eq!(expected, files.unwrap());
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    input::{decode_line, FileIdentity, Position},
+    logging,
+};
+
+/// A complete line of a followed file.
+pub(crate) struct Line {
+    pub line: std::io::Result<String>,
+    /// Identity of the file the line was read from, which changes on rotation.
+    pub identity: Option<FileIdentity>,
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Where to start reading the file found when following starts, later files are read from their beginning.
+enum Start {
+    /// After the last complete line, like `tail -F`.
+    End,
+    /// At the position saved for the file, if it is still the same file, otherwise at its beginning since it replaced that file.
+    Checkpoint(Option<FileIdentity>, Position),
+    Beginning,
+}
+
+/// A plain text file that is read incrementally as it grows, similar to `tail -F`.
+pub(crate) struct FollowedFile {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+    identity: Option<FileIdentity>,
+    start: Start,
+    position: u64,
+    line_num: u64,
+    partial: Vec<u8>,
+    is_missing: bool,
+}
+
+impl FollowedFile {
+    /// Follows a file, skipping its existing lines.
+    pub fn new(path: PathBuf) -> Self {
+        Self::with_start(path, Start::End)
+    }
+
+    /// Follows a file from the position saved for it, if it still has the same identity.
+    pub fn resume(path: PathBuf, identity: Option<FileIdentity>, position: Position) -> Self {
+        Self::with_start(path, Start::Checkpoint(identity, position))
+    }
+
+    fn with_start(path: PathBuf, start: Start) -> Self {
+        Self {
+            path,
+            reader: None,
+            identity: None,
+            start,
+            position: 0,
+            line_num: 0,
+            partial: Vec::new(),
+            is_missing: false,
+        }
+    }
+
+    pub fn name(&self) -> std::path::Display<'_> {
+        self.path.display()
+    }
+
+    /// Reads the complete lines appended since the last call, switching to the new file on rotation.
+    pub fn poll(&mut self) -> Result<Vec<Line>> {
+        if self.reader.is_none() && !self.open()? {
+            return Ok(Vec::new());
+        }
+
+        let mut lines = self.read_lines()?;
+        if lines.is_empty() {
+            let metadata = match std::fs::metadata(&self.path) {
+                Ok(m) => m,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(lines),
+                Err(e) => return Err(e).with_context(|| format!("Failed to check file {}", self.name())),
+            };
+            if FileIdentity::of(&metadata) != self.identity {
+                logging::print_info(&format!("{}: rotated", self.name()));
+                lines = self.read_lines()?;
+                if !self.partial.is_empty() {
+                    lines.push(self.take_line());
+                }
+
+                self.reader = None;
+                if self.open()? {
+                    lines.extend(self.read_lines()?);
+                }
+            } else if metadata.len() < self.position {
+                logging::print_info(&format!("{}: truncated", self.name()));
+                self.rewind()?;
+                lines = self.read_lines()?;
+            }
+        }
+
+        Ok(lines)
+    }
+
+    fn open(&mut self) -> Result<bool> {
+        let start = std::mem::replace(&mut self.start, Start::Beginning);
+        let mut file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if !self.is_missing {
+                    self.is_missing = true;
+                    logging::print_warning(&format!("File {} is missing, waiting for it to appear", self.name()));
+                }
+
+                return Ok(false);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to open file {}", self.name())),
+        };
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to get metadata of file {}", self.name()))?;
+        self.is_missing = false;
+        self.identity = FileIdentity::of(&metadata);
+        let position = match start {
+            Start::End => Position {
+                offset: last_line_end(&mut file, metadata.len())
+                    .with_context(|| format!("Failed to find the end of file {}", self.name()))?,
+                line_num: 0,
+            },
+            Start::Checkpoint(identity, position) if identity == self.identity => {
+                if position.offset <= metadata.len() {
+                    logging::print_info(&format!("{}: resuming at line {}", self.name(), position.line_num + 1));
+                    position
+                } else {
+                    logging::print_warning(&format!(
+                        "{}: file is shorter than its checkpoint, following it from the start",
+                        self.name()
+                    ));
+                    Position::default()
+                }
+            }
+            Start::Checkpoint(..) | Start::Beginning => Position::default(),
+        };
+        file.seek(SeekFrom::Start(position.offset))
+            .with_context(|| format!("Failed to seek in file {}", self.name()))?;
+        self.reader = Some(BufReader::new(file));
+        self.position = position.offset;
+        self.line_num = position.line_num;
+        Ok(true)
+    }
+
+    fn rewind(&mut self) -> Result<()> {
+        if let Some(reader) = self.reader.as_mut() {
+            reader
+                .seek(SeekFrom::Start(0))
+                .with_context(|| format!("Failed to rewind file {}", self.path.display()))?;
+        }
+
+        self.position = 0;
+        self.line_num = 0;
+        self.partial.clear();
+        Ok(())
+    }
+
+    fn read_lines(&mut self) -> Result<Vec<Line>> {
+        const MAX_LINES: usize = 10000;
+        let mut lines = Vec::new();
+        while lines.len() < MAX_LINES {
+            let reader = match self.reader.as_mut() {
+                Some(r) => r,
+                None => break,
+            };
+            let count = reader
+                .read_until(b'\n', &mut self.partial)
+                .with_context(|| format!("Failed to read file {}", self.path.display()))?;
+            if count == 0 {
+                break;
+            }
+
+            self.position += count as u64;
+            if self.partial.last() != Some(&b'\n') {
+                break;
+            }
+
+            lines.push(self.take_line());
+        }
+
+        Ok(lines)
+    }
+
+    fn take_line(&mut self) -> Line {
+        let start = Position {
+            offset: self.position - self.partial.len() as u64,
+            line_num: self.line_num,
+        };
+        self.line_num += 1;
+        Line {
+            line: decode_line(std::mem::take(&mut self.partial)),
+            identity: self.identity,
+            start,
+            end: Position {
+                offset: self.position,
+                line_num: self.line_num,
+            },
+        }
+    }
+}
+
+/// Returns the offset after the last complete line of a file, or its length if the last line is too long to be found.
+///
+/// Line numbers are not known from there on, so they are counted from that line.
+fn last_line_end(file: &mut File, length: u64) -> std::io::Result<u64> {
+    const MAX_TAIL_LENGTH: u64 = 64 * 1024;
+    let tail_start = length.saturating_sub(MAX_TAIL_LENGTH);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(tail_start))?;
+    file.take(MAX_TAIL_LENGTH).read_to_end(&mut tail)?;
+    Ok(match tail.iter().rposition(|&b| b == b'\n') {
+        Some(index) => tail_start + index as u64 + 1,
+        None if tail_start == 0 => 0,
+        None => length,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    fn poll_lines(file: &mut FollowedFile) -> Vec<(u64, String)> {
+        file.poll()
+            .unwrap()
+            .into_iter()
+            .map(|l| (l.end.line_num, l.line.unwrap()))
+            .collect()
+    }
+
+    fn append(path: &std::path::Path, content: &str) {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn followed_file_handles_partial_lines_and_truncation() {
+        let path = std::env::temp_dir().join(format!("sslkeylog-processor-follow-{}.log", std::process::id()));
+        std::fs::write(&path, "old\npartial").unwrap();
+        let mut file = FollowedFile::new(path.clone());
+        assert!(poll_lines(&mut file).is_empty());
+
+        append(&path, " line\nfirst\nsec");
+        assert_eq!(
+            vec![(1, String::from("partial line")), (2, String::from("first"))],
+            poll_lines(&mut file)
+        );
+
+        append(&path, "ond\r\n");
+        assert_eq!(vec![(3, String::from("second"))], poll_lines(&mut file));
+        assert!(poll_lines(&mut file).is_empty());
+
+        std::fs::write(&path, "new\n").unwrap();
+        assert_eq!(vec![(1, String::from("new"))], poll_lines(&mut file));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn followed_file_resumes_at_its_checkpoint() {
+        let path = std::env::temp_dir().join(format!("sslkeylog-processor-follow-resume-{}.log", std::process::id()));
+        std::fs::write(&path, "first\nsecond\n").unwrap();
+        let identity = FileIdentity::of(&std::fs::metadata(&path).unwrap());
+        let checkpoint = Position { offset: 6, line_num: 1 };
+        let lines = FollowedFile::resume(path.clone(), identity, checkpoint).poll().unwrap();
+        let replaced = FollowedFile::resume(path.clone(), Some(FileIdentity { device: 0, inode: 0 }), checkpoint)
+            .poll()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(1, lines.len());
+        assert_eq!("second", lines[0].line.as_ref().unwrap());
+        assert_eq!(
+            (checkpoint, Position { offset: 13, line_num: 2 }),
+            (lines[0].start, lines[0].end)
+        );
+        assert_eq!(2, replaced.len());
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::Result;
+use time::{Duration, OffsetDateTime};
+
+use crate::pcap::Packet;
+
+/// TLS handshake parameters collected from the client and server hellos of a captured connection.
+pub(crate) struct Handshake {
+    pub timestamp: OffsetDateTime,
+    pub server: SocketAddr,
+    pub client_random: Vec<u8>,
+    /// Unknown if the server hello was not captured.
+    pub server_random: Option<Vec<u8>>,
+    pub sni: Option<String>,
+}
+
+/// Collects TLS handshakes from captured packets, reassembling the beginning of each TCP stream.
+pub(crate) fn scan(packets: impl IntoIterator<Item = Result<Packet>>) -> Result<Vec<Handshake>> {
+    // Streams whose end was not captured are dropped once they are idle for this long.
+    const MAX_IDLE: Duration = Duration::minutes(5);
+    let mut streams = HashMap::<(SocketAddr, SocketAddr), Stream>::new();
+    // Streams whose beginning was parsed, kept until they end so that their later segments are not parsed as a hello.
+    let mut finished = HashMap::<(SocketAddr, SocketAddr), OffsetDateTime>::new();
+    // Handshakes waiting for their server hello, by client and server.
+    let mut connections = HashMap::<(SocketAddr, SocketAddr), usize>::new();
+    let mut handshakes = Vec::<Handshake>::new();
+    let mut last_pruned = None;
+    for packet in packets {
+        let packet = packet?;
+        let segment = match parse_packet(packet.link_type, &packet.data) {
+            Some(s) => s,
+            None => continue,
+        };
+        let key = (segment.source, segment.destination);
+        if segment.is_syn {
+            finished.remove(&key);
+            streams.insert(key, Stream::new(segment.sequence.wrapping_add(1), packet.timestamp));
+        } else if let Some(last_seen) = finished.get_mut(&key) {
+            *last_seen = packet.timestamp;
+        } else if !segment.payload.is_empty() {
+            let stream = streams
+                .entry(key)
+                .or_insert_with(|| Stream::new(segment.sequence, packet.timestamp));
+            stream.last_seen = packet.timestamp;
+            match stream.push(segment.sequence, segment.payload) {
+                Some(Hello::Client { random, sni }) => {
+                    connections.insert(key, handshakes.len());
+                    handshakes.push(Handshake {
+                        timestamp: packet.timestamp,
+                        server: segment.destination,
+                        client_random: random,
+                        server_random: None,
+                        sni,
+                    });
+                }
+                // A hello retry request is followed by another server hello, which is not parsed.
+                Some(Hello::Server { random }) => {
+                    if let Some(index) = connections.remove(&(segment.destination, segment.source)) {
+                        if random != HELLO_RETRY_REQUEST_RANDOM {
+                            handshakes[index].server_random = Some(random);
+                        }
+                    }
+                }
+                None => {}
+            }
+
+            if stream.is_done {
+                streams.remove(&key);
+                finished.insert(key, packet.timestamp);
+            }
+        }
+
+        if segment.is_closing {
+            streams.remove(&key);
+            finished.remove(&key);
+        }
+
+        if last_pruned.is_none_or(|t| packet.timestamp - t >= MAX_IDLE) {
+            let oldest = packet.timestamp - MAX_IDLE;
+            streams.retain(|_, s| s.last_seen > oldest);
+            finished.retain(|_, &mut t| t > oldest);
+            connections.retain(|_, &mut i| handshakes[i].timestamp > oldest);
+            last_pruned = Some(packet.timestamp);
+        }
+    }
+
+    Ok(handshakes)
+}
+
+struct Segment<'a> {
+    source: SocketAddr,
+    destination: SocketAddr,
+    sequence: u32,
+    is_syn: bool,
+    /// FIN or RST.
+    is_closing: bool,
+    payload: &'a [u8],
+}
+
+fn parse_packet(link_type: u32, data: &[u8]) -> Option<Segment<'_>> {
+    const LINKTYPE_NULL: u32 = 0;
+    const LINKTYPE_ETHERNET: u32 = 1;
+    const LINKTYPE_RAW: u32 = 101;
+    const LINKTYPE_LINUX_SLL: u32 = 113;
+    const LINKTYPE_IPV4: u32 = 228;
+    const LINKTYPE_IPV6: u32 = 229;
+    const LINKTYPE_LINUX_SLL2: u32 = 276;
+    let ip = match link_type {
+        LINKTYPE_NULL => data.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ether_type = read_u16(data, offset)?;
+            while ether_type == 0x8100 || ether_type == 0x88a8 {
+                offset += 4;
+                ether_type = read_u16(data, offset)?;
+            }
+
+            data.get(offset + 2..)?
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20..)?,
+        _ => return None,
+    };
+    let (source_ip, destination_ip, tcp) = match ip.first()? >> 4 {
+        4 => parse_ipv4(ip)?,
+        6 => parse_ipv6(ip)?,
+        _ => return None,
+    };
+    let data_offset = ((tcp.get(12)? >> 4) as usize) * 4;
+    Some(Segment {
+        source: SocketAddr::new(source_ip, read_u16(tcp, 0)?),
+        destination: SocketAddr::new(destination_ip, read_u16(tcp, 2)?),
+        sequence: u32::from_be_bytes(tcp.get(4..8)?.try_into().ok()?),
+        is_syn: tcp.get(13)? & 0x02 != 0,
+        is_closing: tcp.get(13)? & 0x05 != 0,
+        payload: tcp.get(data_offset..)?,
+    })
+}
+
+fn parse_ipv4(ip: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    const PROTOCOL_TCP: u8 = 6;
+    let header_length = ((ip.first()? & 0x0f) as usize) * 4;
+    let total_length = read_u16(ip, 2)? as usize;
+    let fragment = read_u16(ip, 6)?;
+    if *ip.get(9)? != PROTOCOL_TCP || fragment & 0x3fff != 0 {
+        return None;
+    }
+
+    let source: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+    let destination: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+    Some((
+        IpAddr::V4(Ipv4Addr::from(source)),
+        IpAddr::V4(Ipv4Addr::from(destination)),
+        ip.get(header_length..total_length.min(ip.len()))?,
+    ))
+}
+
+fn parse_ipv6(ip: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    const PROTOCOL_TCP: u8 = 6;
+    const EXTENSION_HEADERS: [u8; 3] = [0, 43, 60];
+    let payload_length = read_u16(ip, 4)? as usize;
+    let source: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+    let destination: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+    let mut next_header = *ip.get(6)?;
+    let mut payload = ip.get(40..(40 + payload_length).min(ip.len()))?;
+    while EXTENSION_HEADERS.contains(&next_header) {
+        next_header = *payload.first()?;
+        payload = payload.get((*payload.get(1)? as usize + 1) * 8..)?;
+    }
+
+    if next_header != PROTOCOL_TCP {
+        return None;
+    }
+
+    Some((
+        IpAddr::V6(Ipv6Addr::from(source)),
+        IpAddr::V6(Ipv6Addr::from(destination)),
+        payload,
+    ))
+}
+
+/// The random of a server hello that asks the client to send another client hello, as defined by RFC 8446.
+const HELLO_RETRY_REQUEST_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91, 0xc2, 0xa2, 0x11, 0x16, 0x7a,
+    0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+enum Hello {
+    Client { random: Vec<u8>, sni: Option<String> },
+    Server { random: Vec<u8> },
+}
+
+/// The beginning of a TCP stream that is kept until it is known whether it starts with a hello.
+struct Stream {
+    initial_sequence: u32,
+    data: Vec<u8>,
+    pending: BTreeMap<usize, Vec<u8>>,
+    is_done: bool,
+    last_seen: OffsetDateTime,
+}
+
+impl Stream {
+    fn new(initial_sequence: u32, timestamp: OffsetDateTime) -> Self {
+        Self {
+            initial_sequence,
+            data: Vec::new(),
+            pending: BTreeMap::new(),
+            is_done: false,
+            last_seen: timestamp,
+        }
+    }
+
+    fn push(&mut self, sequence: u32, payload: &[u8]) -> Option<Hello> {
+        const MAX_LENGTH: usize = 256 * 1024;
+        if self.is_done {
+            return None;
+        }
+
+        let offset = sequence.wrapping_sub(self.initial_sequence) as usize;
+        if offset >= MAX_LENGTH {
+            return None;
+        }
+
+        self.pending.insert(offset, payload.to_vec());
+        while let Some(entry) = self.pending.first_entry() {
+            let offset = *entry.key();
+            if offset > self.data.len() {
+                break;
+            }
+
+            let segment = entry.remove();
+            let skip = self.data.len() - offset;
+            if skip < segment.len() {
+                self.data.extend_from_slice(&segment[skip..]);
+            }
+        }
+
+        match parse_hello(&self.data) {
+            Parsed::Incomplete if self.data.len() < MAX_LENGTH => None,
+            Parsed::Incomplete | Parsed::Invalid => {
+                self.is_done = true;
+                None
+            }
+            Parsed::Hello(hello) => {
+                self.is_done = true;
+                Some(hello)
+            }
+        }
+    }
+}
+
+enum Parsed {
+    Incomplete,
+    Invalid,
+    Hello(Hello),
+}
+
+fn parse_hello(data: &[u8]) -> Parsed {
+    const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+    const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+    const HANDSHAKE_TYPE_SERVER_HELLO: u8 = 2;
+    let mut message = Vec::new();
+    let mut data = data;
+    while !data.is_empty() {
+        if data[0] != CONTENT_TYPE_HANDSHAKE {
+            return Parsed::Invalid;
+        }
+
+        let fragment = match read_u16(data, 3).and_then(|l| data.get(5..5 + l as usize)) {
+            Some(f) => f,
+            None => break,
+        };
+        message.extend_from_slice(fragment);
+        data = &data[5 + fragment.len()..];
+    }
+
+    let message_type = match message.first() {
+        None => return Parsed::Incomplete,
+        Some(&t) if t != HANDSHAKE_TYPE_CLIENT_HELLO && t != HANDSHAKE_TYPE_SERVER_HELLO => return Parsed::Invalid,
+        Some(&t) => t,
+    };
+
+    let length = match message.get(1..4) {
+        Some(l) => u32::from_be_bytes([0, l[0], l[1], l[2]]) as usize,
+        None => return Parsed::Incomplete,
+    };
+    let body = match message.get(4..4 + length) {
+        Some(b) => b,
+        None => return Parsed::Incomplete,
+    };
+    match body.get(2..34) {
+        Some(random) if message_type == HANDSHAKE_TYPE_CLIENT_HELLO => Parsed::Hello(Hello::Client {
+            random: random.to_vec(),
+            sni: parse_sni(body),
+        }),
+        Some(random) => Parsed::Hello(Hello::Server { random: random.to_vec() }),
+        None => Parsed::Invalid,
+    }
+}
+
+fn parse_sni(client_hello: &[u8]) -> Option<String> {
+    const EXTENSION_SERVER_NAME: u16 = 0;
+    const NAME_TYPE_HOST_NAME: u8 = 0;
+    let mut offset = 34;
+    offset += 1 + *client_hello.get(offset)? as usize;
+    offset += 2 + read_u16(client_hello, offset)? as usize;
+    offset += 1 + *client_hello.get(offset)? as usize;
+    let extensions_length = read_u16(client_hello, offset)? as usize;
+    let mut extensions = client_hello.get(offset + 2..offset + 2 + extensions_length)?;
+    while extensions.len() >= 4 {
+        let extension_type = read_u16(extensions, 0)?;
+        let length = read_u16(extensions, 2)? as usize;
+        let data = extensions.get(4..4 + length)?;
+        if extension_type == EXTENSION_SERVER_NAME {
+            let mut names = data.get(2..)?;
+            while names.len() >= 3 {
+                let name_length = read_u16(names, 1)? as usize;
+                let name = names.get(3..3 + name_length)?;
+                if names[0] == NAME_TYPE_HOST_NAME {
+                    return std::str::from_utf8(name).ok().map(String::from);
+                }
+
+                names = &names[3 + name_length..];
+            }
+
+            return None;
+        }
+
+        extensions = &extensions[4 + length..];
+    }
+
+    None
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut server_name = vec![0u8];
+        server_name.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(sni.as_bytes());
+        let mut extension = (server_name.len() as u16).to_be_bytes().to_vec();
+        extension.extend_from_slice(&server_name);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0xaa; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&((extension.len() + 4) as u16).to_be_bytes());
+        body.extend_from_slice(&0u16.to_be_bytes());
+        body.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extension);
+
+        handshake_record(1, &body)
+    }
+
+    fn server_hello(random: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(random);
+        body.extend_from_slice(&[0, 0x13, 0x01, 0, 0, 0]);
+        handshake_record(2, &body)
+    }
+
+    fn handshake_record(message_type: u8, body: &[u8]) -> Vec<u8> {
+        let mut message = vec![message_type, 0];
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(body);
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        record.extend_from_slice(&message);
+        record
+    }
+
+    /// Builds a raw IPv4 packet with a TCP segment.
+    fn packet(source: &str, destination: &str, sequence: u32, flags: u8, payload: &[u8]) -> Result<Packet> {
+        let (source, destination): (SocketAddr, SocketAddr) = (source.parse().unwrap(), destination.parse().unwrap());
+        let mut data = vec![0x45, 0];
+        data.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 64, 6, 0, 0]);
+        for address in [source, destination] {
+            match address.ip() {
+                IpAddr::V4(ip) => data.extend_from_slice(&ip.octets()),
+                IpAddr::V6(_) => panic!("Expected IPv4 address"),
+            }
+        }
+
+        data.extend_from_slice(&source.port().to_be_bytes());
+        data.extend_from_slice(&destination.port().to_be_bytes());
+        data.extend_from_slice(&sequence.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        data.extend_from_slice(payload);
+        Ok(Packet {
+            timestamp: OffsetDateTime::UNIX_EPOCH,
+            link_type: 101,
+            data,
+        })
+    }
+
+    #[test]
+    fn stream_reassembles_client_hello() {
+        let hello = client_hello("example.com");
+        let mut stream = Stream::new(1000, OffsetDateTime::UNIX_EPOCH);
+        assert!(stream.push(1010, &hello[10..]).is_none());
+        match stream.push(1000, &hello[..10]) {
+            Some(Hello::Client { random, sni }) => {
+                assert_eq!(vec![0xaa; 32], random);
+                assert_eq!(Some(String::from("example.com")), sni);
+            }
+            _ => panic!("Expected client hello"),
+        }
+    }
+
+    #[test]
+    fn scan_takes_server_random_from_server_hello() {
+        const SYN: u8 = 0x02;
+        const ACK: u8 = 0x10;
+        let client = "10.0.0.1:50000";
+        let server = "10.0.0.2:443";
+        let retry_client = "10.0.0.1:50001";
+        let handshakes = scan([
+            packet(client, server, 100, SYN, &[]),
+            packet(server, client, 500, SYN | ACK, &[]),
+            packet(client, server, 101, ACK, &client_hello("example.com")),
+            packet(server, client, 501, ACK, &server_hello(&[0xbb; 32])),
+            packet(retry_client, server, 101, ACK, &client_hello("example.com")),
+            packet(server, retry_client, 501, ACK, &server_hello(&HELLO_RETRY_REQUEST_RANDOM)),
+        ])
+        .unwrap();
+
+        assert_eq!(2, handshakes.len());
+        assert_eq!("10.0.0.2:443".parse::<SocketAddr>().unwrap(), handshakes[0].server);
+        assert_eq!(vec![0xaa; 32], handshakes[0].client_random);
+        assert_eq!(Some(vec![0xbb; 32]), handshakes[0].server_random);
+        assert_eq!(Some(String::from("example.com")), handshakes[0].sni);
+        assert_eq!(None, handshakes[1].server_random);
+    }
+
+    #[test]
+    fn scan_forgets_closed_streams() {
+        const ACK: u8 = 0x10;
+        const FIN: u8 = 0x01;
+        let client = "10.0.0.1:50000";
+        let server = "10.0.0.2:443";
+        let hello = client_hello("example.com");
+        let handshakes = scan([
+            packet(client, server, 101, ACK, &hello),
+            // A later segment of the same stream that happens to look like a hello is not parsed
+            packet(client, server, 101 + hello.len() as u32, ACK, &hello),
+            packet(client, server, 101 + 2 * hello.len() as u32, FIN | ACK, &[]),
+            // The endpoints are reused by a connection whose SYN was not captured
+            packet(client, server, 9000, ACK, &hello),
+        ])
+        .unwrap();
+
+        assert_eq!(2, handshakes.len());
+    }
+
+    #[test]
+    fn stream_rejects_non_tls() {
+        let mut stream = Stream::new(0, OffsetDateTime::UNIX_EPOCH);
+        assert!(stream.push(0, b"GET / HTTP/1.1\r\n").is_none());
+        assert!(stream.is_done);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    fn detect(header: &[u8]) -> Self {
+        const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+        const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+        const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+        if header.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Self::Zstd
+        } else if header.starts_with(XZ_MAGIC) {
+            Self::Xz
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// File name denoting the standard input.
+pub(crate) const STDIN_PATH: &str = "-";
+
+/// Opens a file (or the standard input for [`STDIN_PATH`]) for reading,
+/// transparently decompressing gzip, zstd and xz content.
+///
+/// Compression is detected by magic bytes rather than by extension, so rotated files
+/// are handled regardless of their naming.
+pub(crate) fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    if path == Path::new(STDIN_PATH) {
+        return decompress(std::io::stdin().lock(), &"standard input");
+    }
+
+    let file = File::open(path).with_context(|| format!("Failed to open file {}", path.display()))?;
+    decompress(BufReader::new(file), &path.display())
+}
+
+fn decompress<R: BufRead + 'static>(mut reader: R, name: &impl std::fmt::Display) -> Result<Box<dyn BufRead>> {
+    let header = reader
+        .fill_buf()
+        .with_context(|| format!("Failed to read header of {}", name))?;
+    Ok(match Compression::detect(header) {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(
+            zstd::stream::read::Decoder::with_buffer(reader)
+                .with_context(|| format!("Failed to initialize zstd decoder for {}", name))?,
+        )),
+        Compression::Xz => Box::new(BufReader::new(liblzma::bufread::XzDecoder::new_multi_decoder(reader))),
+    })
+}
+
+/// Position in a (decompressed) input stream.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Position {
+    pub offset: u64,
+    pub line_num: u64,
+}
+
+/// A source of lines that keeps track of its position in the underlying stream, for checkpoints.
+pub(crate) trait LineSource: Iterator<Item = std::io::Result<String>> {
+    /// Returns the position after the last line.
+    fn current_position(&self) -> Position;
+
+    /// Skips the content up to the specified position, returning `false` if the input ends before it.
+    fn skip_to(&mut self, position: Position) -> std::io::Result<bool>;
+}
+
+/// Skips the content of a reader from `offset` up to `target`, returning `false` if the input ends before it.
+pub(crate) fn skip(reader: &mut dyn BufRead, offset: &mut u64, target: u64) -> std::io::Result<bool> {
+    let count = target.saturating_sub(*offset);
+    let skipped = std::io::copy(&mut reader.take(count), &mut std::io::sink())?;
+    *offset += skipped;
+    Ok(skipped == count)
+}
+
+/// Iterates over the lines of a reader, keeping track of the position after the last line.
+pub(crate) struct Lines {
+    reader: Box<dyn BufRead>,
+    position: Position,
+}
+
+impl Lines {
+    pub fn new(reader: Box<dyn BufRead>) -> Self {
+        Self {
+            reader,
+            position: Position::default(),
+        }
+    }
+}
+
+impl LineSource for Lines {
+    fn current_position(&self) -> Position {
+        self.position
+    }
+
+    fn skip_to(&mut self, position: Position) -> std::io::Result<bool> {
+        if !skip(&mut self.reader, &mut self.position.offset, position.offset)? {
+            return Ok(false);
+        }
+
+        self.position.line_num = position.line_num;
+        Ok(true)
+    }
+}
+
+impl Iterator for Lines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        match self.reader.read_until(b'\n', &mut line) {
+            Ok(0) => None,
+            Ok(count) => {
+                self.position.offset += count as u64;
+                self.position.line_num += 1;
+                Some(decode_line(line))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Wraps a line source to read lines ahead, e.g. to detect their format, before yielding them.
+pub(crate) struct Peekable<'a> {
+    source: Box<dyn LineSource + 'a>,
+    peeked: VecDeque<(std::io::Result<String>, Position)>,
+    position: Position,
+}
+
+impl<'a> Peekable<'a> {
+    pub fn new(source: Box<dyn LineSource + 'a>) -> Self {
+        let position = source.current_position();
+        Self {
+            source,
+            peeked: VecDeque::new(),
+            position,
+        }
+    }
+
+    /// Reads ahead up to `count` lines, returning the lines read ahead so far.
+    pub fn peek(&mut self, count: usize) -> impl Iterator<Item = &std::io::Result<String>> {
+        while self.peeked.len() < count {
+            match self.source.next() {
+                Some(line) => self.peeked.push_back((line, self.source.current_position())),
+                None => break,
+            }
+        }
+
+        self.peeked.iter().map(|(line, _)| line)
+    }
+}
+
+impl LineSource for Peekable<'_> {
+    fn current_position(&self) -> Position {
+        self.position
+    }
+
+    fn skip_to(&mut self, position: Position) -> std::io::Result<bool> {
+        if !self.peeked.is_empty() {
+            return Err(std::io::Error::other("Cannot skip lines that were read ahead"));
+        }
+
+        let is_reached = self.source.skip_to(position)?;
+        self.position = self.source.current_position();
+        Ok(is_reached)
+    }
+}
+
+impl Iterator for Peekable<'_> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((line, position)) = self.peeked.pop_front() {
+            self.position = position;
+            return Some(line);
+        }
+
+        let line = self.source.next()?;
+        self.position = self.source.current_position();
+        Some(line)
+    }
+}
+
+pub(crate) fn decode_line(mut line: Vec<u8>) -> std::io::Result<String> {
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    }
+
+    String::from_utf8(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct FileIdentity {
+    pub device: u64,
+    pub inode: u64,
+}
+
+impl FileIdentity {
+    #[cfg(unix)]
+    pub fn of(metadata: &std::fs::Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn of(_metadata: &std::fs::Metadata) -> Option<Self> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn detect_uses_magic() {
+        assert_eq!(Compression::Gzip, Compression::detect(&[0x1f, 0x8b, 0x08]));
+        assert_eq!(Compression::Zstd, Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd]));
+        assert_eq!(Compression::Xz, Compression::detect(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]));
+        assert_eq!(Compression::None, Compression::detect(b"2021-01-01T"));
+        assert_eq!(Compression::None, Compression::detect(b""));
+    }
+
+    #[test]
+    fn open_decompresses_gzip() {
+        let path = std::env::temp_dir().join(format!("sslkeylog-processor-input-{}.gz", std::process::id()));
+        let mut encoder = flate2::write::GzEncoder::new(File::create(&path).unwrap(), flate2::Compression::default());
+        encoder.write_all(b"line1\nline2\n").unwrap();
+        encoder.finish().unwrap();
+
+        let mut content = String::new();
+        open(&path).unwrap().read_to_string(&mut content).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("line1\nline2\n", content);
+    }
+
+    #[test]
+    fn peeked_lines_are_still_yielded() {
+        let lines = Lines::new(Box::new(std::io::Cursor::new(b"line1\nline2\nline3\n".to_vec())));
+        let mut lines = Peekable::new(Box::new(lines));
+        assert_eq!(2, lines.peek(2).count());
+        assert_eq!(Position::default(), lines.current_position());
+
+        assert_eq!("line1", lines.next().unwrap().unwrap());
+        assert_eq!(Position { offset: 6, line_num: 1 }, lines.current_position());
+        assert_eq!(vec!["line2", "line3"], lines.map(|l| l.unwrap()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Read},
+};
+
+use time::OffsetDateTime;
+
+use crate::{
+    data_model,
+    input::{self, LineSource, Position},
+};
+
+/// Selects the journal entries by `SYSLOG_IDENTIFIER` or `_SYSTEMD_UNIT`, accepting all entries if both lists are empty.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Filter {
+    pub identifiers: Vec<String>,
+    pub units: Vec<String>,
+}
+
+impl Filter {
+    fn accepts(&self, fields: &HashMap<String, Vec<u8>>) -> bool {
+        if self.identifiers.is_empty() && self.units.is_empty() {
+            return true;
+        }
+
+        let matches = |name: &str, values: &[String]| {
+            fields
+                .get(name)
+                .is_some_and(|v| values.iter().any(|value| value.as_bytes() == v.as_slice()))
+        };
+        matches("SYSLOG_IDENTIFIER", &self.identifiers) || matches("_SYSTEMD_UNIT", &self.units)
+    }
+}
+
+/// Iterates over the `MESSAGE` fields of a journal export stream (`journalctl -o export`).
+///
+/// A message that does not start with an RFC 3339 timestamp is prefixed with the entry's realtime timestamp,
+/// so it can be parsed like a DDG syslog line. The position counts entries in place of lines.
+pub(crate) struct Messages<'a> {
+    reader: Box<dyn BufRead>,
+    filter: &'a Filter,
+    position: Position,
+}
+
+impl<'a> Messages<'a> {
+    pub fn new(reader: Box<dyn BufRead>, filter: &'a Filter) -> Self {
+        Self {
+            reader,
+            filter,
+            position: Position::default(),
+        }
+    }
+
+    /// Reads the fields of the next entry, or `None` at the end of the stream.
+    fn read_entry(&mut self) -> std::io::Result<Option<HashMap<String, Vec<u8>>>> {
+        let mut fields = HashMap::new();
+        loop {
+            let mut line = Vec::new();
+            let count = self.reader.read_until(b'\n', &mut line)?;
+            self.position.offset += count as u64;
+            if count == 0 {
+                return Ok(if fields.is_empty() { None } else { Some(fields) });
+            }
+
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+
+            if line.is_empty() {
+                if fields.is_empty() {
+                    continue;
+                }
+
+                return Ok(Some(fields));
+            }
+
+            let (name, value) = match line.iter().position(|&b| b == b'=') {
+                Some(i) => (line[..i].to_vec(), line[i + 1..].to_vec()),
+                None => {
+                    // Binary field: the name is followed by a little-endian 64-bit length, the data and a newline
+                    let mut length = [0; 8];
+                    self.reader.read_exact(&mut length)?;
+                    let length = u64::from_le_bytes(length);
+                    let mut value = Vec::new();
+                    (&mut self.reader).take(length).read_to_end(&mut value)?;
+                    let mut newline = [0; 1];
+                    self.reader.read_exact(&mut newline)?;
+                    if value.len() as u64 != length || newline[0] != b'\n' {
+                        return Err(invalid_data("Truncated binary journal field"));
+                    }
+
+                    self.position.offset += 8 + length + 1;
+                    (line, value)
+                }
+            };
+            let name = String::from_utf8(name).map_err(|_| invalid_data("Invalid journal field name"))?;
+            fields.insert(name, value);
+        }
+    }
+}
+
+impl LineSource for Messages<'_> {
+    fn current_position(&self) -> Position {
+        self.position
+    }
+
+    fn skip_to(&mut self, position: Position) -> std::io::Result<bool> {
+        if !input::skip(&mut self.reader, &mut self.position.offset, position.offset)? {
+            return Ok(false);
+        }
+
+        self.position.line_num = position.line_num;
+        Ok(true)
+    }
+}
+
+impl Iterator for Messages<'_> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let fields = match self.read_entry() {
+                Ok(Some(f)) => f,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            self.position.line_num += 1;
+            if !self.filter.accepts(&fields) {
+                continue;
+            }
+
+            if let Some(message) = fields.get("MESSAGE") {
+                return Some(to_line(message, &fields));
+            }
+        }
+    }
+}
+
+fn to_line(message: &[u8], fields: &HashMap<String, Vec<u8>>) -> std::io::Result<String> {
+    let message = input::decode_line(message.to_vec())?;
+    if data_model::starts_with_timestamp(&message) {
+        return Ok(message);
+    }
+
+    let timestamp = fields
+        .get("__REALTIME_TIMESTAMP")
+        .and_then(|t| std::str::from_utf8(t).ok())
+        .and_then(|t| t.parse::<i128>().ok())
+        .and_then(|t| OffsetDateTime::from_unix_timestamp_nanos(t * 1000).ok())
+        .ok_or_else(|| invalid_data("Missing journal realtime timestamp"))?;
+    Ok(data_model::prefix_timestamp(&message, timestamp))
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn messages_are_filtered_and_timestamped() {
+        let mut export = Vec::new();
+        export.extend_from_slice(b"__REALTIME_TIMESTAMP=1609556645000000\nSYSLOG_IDENTIFIER=sslkeylog\nMESSAGE=first\n\n");
+        export.extend_from_slice(b"__REALTIME_TIMESTAMP=1609556646000000\nSYSLOG_IDENTIFIER=other\nMESSAGE=skipped\n\n");
+        export.extend_from_slice(b"__REALTIME_TIMESTAMP=1609556647000000\n_SYSTEMD_UNIT=sslkeylog.service\nMESSAGE\n");
+        export.extend_from_slice(&27u64.to_le_bytes());
+        export.extend_from_slice(b"2021-01-01T00:00:00Z second\n\n");
+        let filter = Filter {
+            identifiers: vec![String::from("sslkeylog")],
+            units: vec![String::from("sslkeylog.service")],
+        };
+
+        let mut messages = Messages::new(Box::new(std::io::Cursor::new(export.clone())), &filter);
+        assert_eq!("2021-01-02T03:04:05Z first", messages.next().unwrap().unwrap());
+        assert_eq!("2021-01-01T00:00:00Z second", messages.next().unwrap().unwrap());
+        assert!(messages.next().is_none());
+        assert_eq!(
+            Position {
+                offset: export.len() as u64,
+                line_num: 3
+            },
+            messages.current_position()
+        );
+    }
+}
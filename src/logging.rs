@@ -1,50 +1,154 @@
-pub(crate) fn print_error<T: std::fmt::Debug>(err: &T) {
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use anyhow::bail;
+use serde_json::{Map, Value};
+
+/// Environment variable selecting the log level when `--log-level` is missing.
+pub(crate) const LEVEL_VARIABLE: &str = "SSLKEYLOG_PROCESSOR_LOG_LEVEL";
+/// Environment variable selecting the log format when `--log-format` is missing.
+pub(crate) const FORMAT_VARIABLE: &str = "SSLKEYLOG_PROCESSOR_LOG_FORMAT";
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static IS_JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl TryFrom<&str> for Level {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => bail!("Unsupported log level {}", value),
+        }
+    }
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    /// Returns the systemd journal priority prefix.
     #[cfg(unix)]
-    {
-        const SD_ERR: &str = "<3>";
-        lazy_static! {
-            static ref PREFIX: &'static str = match std::env::var("INVOCATION_ID") {
-                Ok(_) => SD_ERR,
-                Err(_) => "",
-            };
+    fn journal_prefix(self) -> &'static str {
+        match self {
+            Level::Error => "<3>",
+            Level::Warn => "<4>",
+            Level::Info => "<6>",
+            Level::Debug | Level::Trace => "<7>",
         }
-        let prefix = &PREFIX;
-        if prefix.is_empty() {
-            eprintln!("Error: {:?}", err);
-        } else {
-            let mut message = String::new();
-            for line in format!("Error: {:?}", err).lines() {
-                if !message.is_empty() {
-                    message.push('\n');
-                }
+    }
+}
 
-                message.push_str(prefix);
-                message.push_str(line);
-            }
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Format {
+    Text,
+    /// One JSON object per message, with the fields and the error chain as separate keys.
+    Json,
+}
 
-            eprintln!("{}", message);
+impl TryFrom<&str> for Format {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => bail!("Unsupported log format {}", value),
         }
     }
-    #[cfg(not(unix))]
-    eprintln!("Error: {:?}", err);
 }
 
-pub(crate) fn print_warning<T: std::fmt::Debug>(err: &T) {
+/// A structured field of a message, e.g. `("file", &file_name)`.
+pub(crate) type Field<'a> = (&'static str, &'a dyn fmt::Display);
+
+pub(crate) fn init(level: Level, format: Format) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    IS_JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+pub(crate) fn is_enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Logs a message to the standard error, keeping the standard output for data.
+pub(crate) fn log(level: Level, message: &str, fields: &[Field]) {
+    if is_enabled(level) {
+        write(level, message, None, fields);
+    }
+}
+
+/// Logs an error along with its chain of causes.
+pub(crate) fn log_error(level: Level, err: &anyhow::Error, fields: &[Field]) {
+    if is_enabled(level) {
+        write(level, &err.to_string(), Some(err), fields);
+    }
+}
+
+fn write(level: Level, message: &str, err: Option<&anyhow::Error>, fields: &[Field]) {
+    if IS_JSON.load(Ordering::Relaxed) {
+        let mut object = Map::new();
+        object.insert(
+            String::from("timestamp"),
+            Value::from(
+                time::OffsetDateTime::now_utc()
+                    .format(&time::format_description::well_known::Rfc3339)
+                    .unwrap_or_default(),
+            ),
+        );
+        object.insert(String::from("level"), Value::from(level.name()));
+        object.insert(String::from("message"), Value::from(message));
+        for (name, value) in fields {
+            object.insert(String::from(*name), Value::from(value.to_string()));
+        }
+
+        if let Some(err) = err {
+            let chain = err.chain().skip(1).map(|e| Value::from(e.to_string())).collect();
+            object.insert(String::from("error_chain"), Value::Array(chain));
+        }
+
+        eprintln!("{}", Value::Object(object));
+        return;
+    }
+
+    let text = match (level, err) {
+        (Level::Error, Some(err)) => format!("Error: {:?}", err),
+        (Level::Error, None) => format!("Error: {}", message),
+        (Level::Warn, Some(err)) => format!("Warning: {:?}", err),
+        (Level::Warn, None) => format!("Warning: {}", message),
+        (_, Some(err)) => format!("{:?}", err),
+        (_, None) => String::from(message),
+    };
     #[cfg(unix)]
     {
-        const SD_ERR: &str = "<4>";
         lazy_static! {
-            static ref PREFIX: &'static str = match std::env::var("INVOCATION_ID") {
-                Ok(_) => SD_ERR,
-                Err(_) => "",
-            };
+            static ref IS_JOURNAL: bool = std::env::var("INVOCATION_ID").is_ok();
         }
-        let prefix = &PREFIX;
-        if prefix.is_empty() {
-            eprintln!("Warning: {:?}", err);
-        } else {
+        if *IS_JOURNAL {
+            let prefix = level.journal_prefix();
             let mut message = String::new();
-            for line in format!("Warning: {:?}", err).lines() {
+            for line in text.lines() {
                 if !message.is_empty() {
                     message.push('\n');
                 }
@@ -54,16 +158,44 @@ pub(crate) fn print_warning<T: std::fmt::Debug>(err: &T) {
             }
 
             eprintln!("{}", message);
+            return;
         }
     }
-    #[cfg(not(unix))]
-    println!("Warning: {:?}", err);
+
+    eprintln!("{}", text);
 }
 
+/// Logs an error, or a warning for a termination.
 pub(crate) fn print(err: &anyhow::Error) {
+    print_with(err, &[]);
+}
+
+/// Logs an error like [`print`], with structured fields such as the file and line.
+pub(crate) fn print_with(err: &anyhow::Error, fields: &[Field]) {
     if err.is::<crate::errors::TerminatedError>() {
-        print_warning(err);
+        log_error(Level::Warn, err, fields);
     } else {
-        print_error(err);
+        log_error(Level::Error, err, fields);
+    }
+}
+
+pub(crate) fn print_warning(message: &str) {
+    log(Level::Warn, message, &[]);
+}
+
+/// Prints a progress message to the standard error, keeping the standard output for data.
+pub(crate) fn print_info(message: &str) {
+    log(Level::Info, message, &[]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn levels_parse_and_order_by_verbosity() {
+        assert_eq!(Level::Debug, Level::try_from("debug").unwrap());
+        assert!(Level::try_from("verbose").is_err());
+        assert!(Level::Error < Level::Warn && Level::Info < Level::Trace);
     }
 }
@@ -0,0 +1,45 @@
+use std::{collections::HashSet, io::Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{configuration::LookupOptions, data_model::CollectionName, logging, nss, output, storage::Storage};
+
+/// Writes the keys of the specified client randoms as an NSS key log.
+pub(crate) fn lookup(options: &LookupOptions, store: &mut dyn Storage) -> Result<()> {
+    let filter = |name: &CollectionName| {
+        options.sni.as_ref().map(|s| *s == name.sni).unwrap_or(true)
+            && options.server_ip.map(|a| a == name.server_ip).unwrap_or(true)
+            && options.server_port.map(|p| p == name.server_port).unwrap_or(true)
+            && options.date.map(|d| d == name.date).unwrap_or(true)
+    };
+    let records = store.lookup(&options.client_randoms, &filter)?;
+
+    let mut output = output::open(&options.output)?;
+    let mut found = HashSet::new();
+    for record in &records {
+        output
+            .write_all(nss::format_record(record.as_ref()).as_bytes())
+            .context("Failed to write keys")?;
+        found.insert(record.get_metadata().client_random.as_slice());
+    }
+
+    output.flush().context("Failed to write keys")?;
+
+    let mut missing = 0;
+    for client_random in &options.client_randoms {
+        if !found.contains(client_random.as_slice()) {
+            logging::print_warning(&format!("No keys found for client random {}", hex::encode(client_random)));
+            missing += 1;
+        }
+    }
+
+    if missing != 0 {
+        bail!(
+            "Keys not found for {} of {} client randoms",
+            missing,
+            options.client_randoms.len()
+        );
+    }
+
+    Ok(())
+}
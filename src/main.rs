@@ -1,10 +1,28 @@
+mod capture;
+mod checkpoint;
+mod completion;
 mod configuration;
 mod data_model;
+mod discovery;
 mod errors;
+mod follow;
+mod handshake;
+mod input;
+mod journal;
 mod logging;
+mod lookup;
+mod metrics;
+mod nss;
+mod output;
+mod pcap;
 mod process;
 mod processor;
+mod redaction;
+mod reject;
+mod spool;
+mod stats;
 mod storage;
+mod syslog;
 mod to_bson;
 
 #[macro_use]
@@ -33,6 +51,7 @@ fn try_main() -> Result<()> {
         return Ok(());
     };
 
+    logging::init(args.log_level, args.log_format);
     let term_token = Arc::new(AtomicBool::new(false));
     register_signal(&term_token)?;
     process::process(&args, &term_token)?;
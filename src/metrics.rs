@@ -0,0 +1,310 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+
+use crate::logging;
+
+const PREFIX: &str = "sslkeylog_processor";
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const SIZE_BUCKETS: [f64; 5] = [1.0, 10.0, 100.0, 1000.0, 10000.0];
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+const PARSE_ERRORS: [ParseError; 3] = [ParseError::Read, ParseError::Format, ParseError::Nss];
+
+/// Reason of a line that did not produce a record.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum ParseError {
+    /// The line could not be read, e.g. invalid UTF-8.
+    Read,
+    /// The line matches none of the record formats.
+    Format,
+    /// The line is not a valid NSS key log line or lacks its metadata.
+    Nss,
+}
+
+impl ParseError {
+    pub fn label(self) -> &'static str {
+        match self {
+            ParseError::Read => "read",
+            ParseError::Format => "format",
+            ParseError::Nss => "nss",
+        }
+    }
+}
+
+struct Histogram<const N: usize> {
+    buckets: [f64; N],
+    counts: [u64; N],
+    sum: f64,
+    count: u64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(buckets: [f64; N]) -> Self {
+        Self {
+            buckets,
+            counts: [0; N],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, count) in self.buckets.iter().zip(&mut self.counts) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+        _ = writeln!(out, "# TYPE {}_{} histogram", PREFIX, name);
+        for (bucket, count) in self.buckets.iter().zip(&self.counts) {
+            _ = writeln!(out, "{}_{}_bucket{{le=\"{}\"}} {}", PREFIX, name, bucket, count);
+        }
+
+        _ = writeln!(out, "{}_{}_bucket{{le=\"+Inf\"}} {}", PREFIX, name, self.count);
+        _ = writeln!(out, "{}_{}_sum {}", PREFIX, name, self.sum);
+        _ = writeln!(out, "{}_{}_count {}", PREFIX, name, self.count);
+    }
+}
+
+/// Process-wide metrics, rendered in the Prometheus text exposition format.
+struct Metrics {
+    lines: u64,
+    parse_errors: [u64; PARSE_ERRORS.len()],
+    pre13_records: u64,
+    tls13_records: u64,
+    write_duration: Histogram<11>,
+    write_size: Histogram<5>,
+    write_errors: u64,
+    pending_batches: usize,
+    pending_records: usize,
+    last_flush: Option<SystemTime>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            lines: 0,
+            parse_errors: [0; PARSE_ERRORS.len()],
+            pre13_records: 0,
+            tls13_records: 0,
+            write_duration: Histogram::new(DURATION_BUCKETS),
+            write_size: Histogram::new(SIZE_BUCKETS),
+            write_errors: 0,
+            pending_batches: 0,
+            pending_records: 0,
+            last_flush: None,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, values: &[(String, u64)]| {
+            _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            _ = writeln!(out, "# TYPE {}_{} counter", PREFIX, name);
+            for (labels, value) in values {
+                _ = writeln!(out, "{}_{}{} {}", PREFIX, name, labels, value);
+            }
+        };
+        let label = |name: &str, value: &str| format!("{{{}=\"{}\"}}", name, value);
+        counter(
+            "lines_total",
+            "Lines read from the input files.",
+            &[(String::new(), self.lines)],
+        );
+        counter(
+            "parse_errors_total",
+            "Lines that did not produce a record, by reason.",
+            &PARSE_ERRORS.map(|e| (label("reason", e.label()), self.parse_errors[e as usize])),
+        );
+        counter(
+            "records_total",
+            "Records parsed from the input files, by TLS version.",
+            &[
+                (label("version", "pre13"), self.pre13_records),
+                (label("version", "tls13"), self.tls13_records),
+            ],
+        );
+        counter(
+            "write_errors_total",
+            "Batches that failed to be written to the storage.",
+            &[(String::new(), self.write_errors)],
+        );
+        self.write_duration
+            .render(&mut out, "write_duration_seconds", "Duration of batch writes to the storage.");
+        self.write_size.render(
+            &mut out,
+            "write_records",
+            "Number of records per batch written to the storage.",
+        );
+
+        let mut gauge = |name: &str, help: &str, value: f64| {
+            _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            _ = writeln!(out, "# TYPE {}_{} gauge", PREFIX, name);
+            _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+        };
+        gauge(
+            "pending_batches",
+            "Batches waiting to be written to the storage.",
+            self.pending_batches as f64,
+        );
+        gauge(
+            "pending_records",
+            "Records waiting to be written to the storage.",
+            self.pending_records as f64,
+        );
+        gauge(
+            "last_flush_timestamp_seconds",
+            "Unix time of the last successful write to the storage, 0 if none.",
+            self.last_flush
+                .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+                .map_or(0.0, |d| d.as_secs_f64()),
+        );
+        out
+    }
+}
+
+fn with_metrics<T>(f: impl FnOnce(&mut Metrics) -> T) -> T {
+    f(&mut METRICS.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+pub(crate) fn count_line() {
+    with_metrics(|m| m.lines += 1);
+}
+
+pub(crate) fn count_parse_error(reason: ParseError) {
+    with_metrics(|m| m.parse_errors[reason as usize] += 1);
+}
+
+pub(crate) fn count_record(is_tls13: bool) {
+    with_metrics(|m| {
+        if is_tls13 {
+            m.tls13_records += 1;
+        } else {
+            m.pre13_records += 1;
+        }
+    });
+}
+
+/// Records a batch write to the storage, `is_success` tells whether it succeeded.
+pub(crate) fn observe_write(size: usize, duration: Duration, is_success: bool) {
+    with_metrics(|m| {
+        m.write_duration.observe(duration.as_secs_f64());
+        m.write_size.observe(size as f64);
+        if is_success {
+            m.last_flush = Some(SystemTime::now());
+        } else {
+            m.write_errors += 1;
+        }
+    });
+}
+
+pub(crate) fn set_pending(batches: usize, records: usize) {
+    with_metrics(|m| {
+        m.pending_batches = batches;
+        m.pending_records = records;
+    });
+}
+
+pub(crate) fn render() -> String {
+    with_metrics(|m| m.render())
+}
+
+/// Serves the metrics over HTTP at `/metrics` from a background thread.
+pub(crate) fn serve(address: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(address).with_context(|| format!("Failed to listen on {}", address))?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.map_err(anyhow::Error::from).and_then(respond) {
+                logging::log_error(logging::Level::Warn, &e.context("Failed to serve metrics"), &[]);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> Result<()> {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => ("405 Method Not Allowed", String::from("Method not allowed\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Writes the metrics for the node exporter textfile collector, replacing the file atomically.
+pub(crate) fn write_textfile(path: &str) -> Result<()> {
+    let path = Path::new(path);
+    let mut temp_name = path.as_os_str().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    // Readable by the node exporter, which usually runs as another user, since the metrics hold no secrets;
+    // a leftover file is removed so that it does not keep its permissions
+    let _ = std::fs::remove_file(&temp_path);
+    let mut file = File::create(&temp_path).with_context(|| format!("Failed to create {}", temp_path.display()))?;
+    file.write_all(render().as_bytes())
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    drop(file);
+    std::fs::rename(&temp_path, path).with_context(|| format!("Failed to rename {} to {}", temp_path.display(), path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metrics_render_in_text_format() {
+        let mut metrics = Metrics::new();
+        metrics.lines = 3;
+        metrics.parse_errors[ParseError::Format as usize] = 1;
+        metrics.write_duration.observe(0.02);
+        metrics.pending_records = 5;
+        let text = metrics.render();
+
+        assert!(text.contains("sslkeylog_processor_lines_total 3\n"));
+        assert!(text.contains("sslkeylog_processor_parse_errors_total{reason=\"format\"} 1\n"));
+        assert!(text.contains("sslkeylog_processor_parse_errors_total{reason=\"read\"} 0\n"));
+        assert!(text.contains("sslkeylog_processor_write_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("sslkeylog_processor_write_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("sslkeylog_processor_write_duration_seconds_count 1\n"));
+        assert!(text.contains("sslkeylog_processor_pending_records 5\n"));
+        assert!(text.contains("sslkeylog_processor_last_flush_timestamp_seconds 0\n"));
+    }
+}
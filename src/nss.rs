@@ -0,0 +1,451 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::BufRead,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+use crate::{
+    data_model::{tls_secret_try_from, RecordMetadata, RecordMetadataSource, Tls13Record, TlsPre13Record, TlsRecord, TlsSecrets},
+    redaction,
+};
+
+/// Connection metadata for NSS key log lines, keyed by client random.
+///
+/// NSS lines only carry the client random, so everything else needed to store a record
+/// comes from a sidecar file with lines in the following format:
+/// `<timestamp> <client_ip>:<client_port> <server_ip>:<server_port> <sni> <server_random> <client_random>`
+#[derive(Default)]
+pub(crate) struct Metadata {
+    entries: HashMap<Vec<u8>, RecordMetadata>,
+}
+
+impl Metadata {
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open NSS metadata file {}", path.display()))?;
+        let mut metadata = Self::default();
+        for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let location = format!("{}:{}", path.display(), index + 1);
+            let line = line.with_context(|| format!("Failed to read NSS metadata at {}", location))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            metadata
+                .add(line)
+                .with_context(|| format!("Failed to parse NSS metadata at {}", location))?;
+        }
+
+        Ok(metadata)
+    }
+
+    /// Adds the metadata from a line in the sidecar file format.
+    pub fn add(&mut self, value: &str) -> Result<()> {
+        const FILTER_REGEX_PATTERN: &str = r"^(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(?:\.\d+)?Z) (\S+?):(?:\d{1,5}) (\S+?):(\d{1,5}) (\S*) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{64})$";
+        lazy_static! {
+            static ref FILTER_REGEX: Regex = Regex::new(FILTER_REGEX_PATTERN).expect("Failed to parse NSS metadata filter regex");
+        }
+
+        let captures = FILTER_REGEX
+            .captures(value)
+            .with_context(|| format!("Invalid NSS metadata line {}", value))?;
+        let metadata = RecordMetadata::try_from(&RecordMetadataSource {
+            timestamp: &captures[1],
+            client_ip: &captures[2],
+            server_ip: &captures[3],
+            server_port: &captures[4],
+            sni: &captures[5],
+            server_random: &captures[6],
+            client_random: &captures[7],
+        })?;
+        self.entries.insert(metadata.client_random.clone(), metadata);
+        Ok(())
+    }
+
+    fn get(&self, client_random: &[u8]) -> Result<RecordMetadata> {
+        self.entries
+            .get(client_random)
+            .cloned()
+            .with_context(|| format!("Missing metadata for client random {}", hex::encode(client_random)))
+    }
+}
+
+enum Slot {
+    ServerHandshake,
+    ClientHandshake,
+    Server0,
+    Client0,
+}
+
+struct PendingTls13 {
+    server_handshake: Option<Vec<u8>>,
+    client_handshake: Option<Vec<u8>>,
+    server_0: Option<Vec<u8>>,
+    client_0: Option<Vec<u8>>,
+    lines: Vec<PendingLine>,
+    created: Instant,
+}
+
+impl PendingTls13 {
+    fn new() -> Self {
+        Self {
+            server_handshake: None,
+            client_handshake: None,
+            server_0: None,
+            client_0: None,
+            lines: Vec::new(),
+            created: Instant::now(),
+        }
+    }
+}
+
+/// A line of a TLS 1.3 session that is still missing some of its secrets.
+pub(crate) struct PendingLine {
+    pub file_name: String,
+    pub line_num: u64,
+    pub line: String,
+}
+
+/// A TLS 1.3 session that was dropped before all of its secrets were known.
+pub(crate) struct Incomplete {
+    pub client_random: Vec<u8>,
+    pub lines: Vec<PendingLine>,
+}
+
+/// Groups NSS key log lines into records.
+///
+/// TLS pre-1.3 sessions are described by a single `CLIENT_RANDOM` line, while TLS 1.3 ones
+/// are spread over several labeled lines that are collected until all four traffic secrets are known.
+pub(crate) struct Assembler<'a> {
+    metadata: &'a Metadata,
+    pending: HashMap<Vec<u8>, PendingTls13>,
+    /// Earlier lines of the TLS 1.3 session that failed to be completed by the last line.
+    failed_lines: Vec<PendingLine>,
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(metadata: &'a Metadata) -> Self {
+        Self {
+            metadata,
+            pending: HashMap::new(),
+            failed_lines: Vec::new(),
+        }
+    }
+
+    /// Parses a line, returning a record once complete. The location is kept with the lines of incomplete records.
+    pub fn push(&mut self, value: &str, file_name: &dyn std::fmt::Display, line_num: u64) -> Result<Option<Box<dyn TlsRecord>>> {
+        let value = value.trim();
+        if value.is_empty() || value.starts_with('#') {
+            return Ok(None);
+        }
+
+        const FILTER_REGEX_PATTERN: &str = r"^([A-Z_0-9]+) ([0-9a-fA-F]{64}) ([0-9a-fA-F]{16,})$";
+        lazy_static! {
+            static ref FILTER_REGEX: Regex = Regex::new(FILTER_REGEX_PATTERN).expect("Failed to parse NSS filter regex");
+        }
+
+        let captures = FILTER_REGEX
+            .captures(value)
+            .with_context(|| format!("Invalid NSS line {}", redaction::line(value)))?;
+        let label = &captures[1];
+        let client_random = hex::decode(&captures[2]).with_context(|| format!("Invalid client random {}", &captures[2]))?;
+        let secret = &captures[3];
+        let (slot, kind) = match label {
+            "CLIENT_RANDOM" => {
+                let metadata = self.metadata.get(&client_random)?;
+                let premaster = tls_secret_try_from(secret, "premaster")?;
+                return Ok(Some(Box::from(TlsPre13Record { metadata, premaster })));
+            }
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET" => (Slot::ServerHandshake, "server handshake"),
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET" => (Slot::ClientHandshake, "client handshake"),
+            "SERVER_TRAFFIC_SECRET_0" => (Slot::Server0, "server initial"),
+            "CLIENT_TRAFFIC_SECRET_0" => (Slot::Client0, "client initial"),
+            "CLIENT_EARLY_TRAFFIC_SECRET" | "EARLY_EXPORTER_SECRET" | "EXPORTER_SECRET" => return Ok(None),
+            _ => bail!("Unsupported NSS label {}", label),
+        };
+        let secret = tls_secret_try_from(secret, kind)?;
+        let pending = self.pending.entry(client_random.clone()).or_insert_with(PendingTls13::new);
+        *match slot {
+            Slot::ServerHandshake => &mut pending.server_handshake,
+            Slot::ClientHandshake => &mut pending.client_handshake,
+            Slot::Server0 => &mut pending.server_0,
+            Slot::Client0 => &mut pending.client_0,
+        } = Some(secret);
+        pending.lines.push(PendingLine {
+            file_name: file_name.to_string(),
+            line_num,
+            line: String::from(value),
+        });
+
+        self.complete(client_random)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns the earlier lines of the TLS 1.3 session whose last line failed to be parsed because of its metadata,
+    /// so that they can be reported along with it.
+    pub fn take_failed_lines(&mut self) -> Vec<PendingLine> {
+        std::mem::take(&mut self.failed_lines)
+    }
+
+    /// Clears the pending state, returning the TLS 1.3 sessions that are still missing some of their secrets.
+    pub fn finish(&mut self) -> Vec<Incomplete> {
+        self.pending
+            .drain()
+            .map(|(client_random, pending)| Incomplete {
+                client_random,
+                lines: pending.lines,
+            })
+            .collect()
+    }
+
+    /// Drops the TLS 1.3 sessions that have been missing some of their secrets for `max_age`,
+    /// and the oldest ones beyond `max_count`, returning them.
+    ///
+    /// Used when following files, which never end, so that sessions whose lines were lost do not accumulate.
+    pub fn expire(&mut self, max_age: Duration, max_count: usize) -> Vec<Incomplete> {
+        if self.pending.is_empty() {
+            return Vec::new();
+        }
+
+        let mut entries: Vec<_> = self.pending.iter().map(|(r, p)| (p.created, r.clone())).collect();
+        entries.sort();
+        let excess = entries.len().saturating_sub(max_count);
+        entries
+            .into_iter()
+            .enumerate()
+            .take_while(|(index, (created, _))| *index < excess || created.elapsed() >= max_age)
+            .map(|(_, (_, client_random))| {
+                let pending = self.pending.remove(&client_random).unwrap();
+                Incomplete {
+                    client_random,
+                    lines: pending.lines,
+                }
+            })
+            .collect()
+    }
+
+    fn complete(&mut self, client_random: Vec<u8>) -> Result<Option<Box<dyn TlsRecord>>> {
+        let is_complete = self
+            .pending
+            .get(&client_random)
+            .map(|p| p.server_handshake.is_some() && p.client_handshake.is_some() && p.server_0.is_some() && p.client_0.is_some())
+            .unwrap_or(false);
+        if !is_complete {
+            return Ok(None);
+        }
+
+        let metadata = self.metadata.get(&client_random);
+        let mut pending = self.pending.remove(&client_random).unwrap();
+        let metadata = match metadata {
+            Ok(m) => m,
+            Err(e) => {
+                pending.lines.pop();
+                self.failed_lines = pending.lines;
+                return Err(e);
+            }
+        };
+        Ok(Some(Box::from(Tls13Record {
+            metadata,
+            server_handshake: pending.server_handshake.unwrap(),
+            client_handshake: pending.client_handshake.unwrap(),
+            server_0: pending.server_0.unwrap(),
+            client_0: pending.client_0.unwrap(),
+        })))
+    }
+}
+
+/// Formats the record metadata as a line in the sidecar file format, with an unknown client port.
+pub(crate) fn format_metadata(metadata: &RecordMetadata) -> String {
+    let timestamp = metadata
+        .timestamp
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default();
+    format!(
+        "{} {}:0 {}:{} {} {} {}\n",
+        timestamp,
+        metadata.client_ip,
+        metadata.server_ip,
+        metadata.server_port,
+        metadata.sni,
+        hex::encode(&metadata.server_random),
+        hex::encode(&metadata.client_random)
+    )
+}
+
+/// Formats a record as NSS key log lines that can be loaded by Wireshark.
+pub(crate) fn format_record(record: &dyn TlsRecord) -> String {
+    let client_random = hex::encode(&record.get_metadata().client_random);
+    match record.get_secrets() {
+        TlsSecrets::Pre13 { premaster } => format!("CLIENT_RANDOM {} {}\n", client_random, hex::encode(premaster)),
+        TlsSecrets::Tls13 {
+            server_handshake,
+            client_handshake,
+            server_0,
+            client_0,
+        } => [
+            ("SERVER_HANDSHAKE_TRAFFIC_SECRET", server_handshake),
+            ("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_handshake),
+            ("SERVER_TRAFFIC_SECRET_0", server_0),
+            ("CLIENT_TRAFFIC_SECRET_0", client_0),
+        ]
+        .iter()
+        .map(|(label, secret)| format!("{} {} {}\n", label, client_random, hex::encode(secret)))
+        .collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLIENT_RANDOM: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+    const SERVER_RANDOM: &str = "ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+
+    fn metadata() -> Metadata {
+        let mut metadata = Metadata::default();
+        metadata
+            .add(&format!(
+                "2021-01-02T03:04:05Z 10.0.0.1:50000 10.0.0.2:443 example.com {} {}",
+                SERVER_RANDOM, CLIENT_RANDOM
+            ))
+            .unwrap();
+        metadata
+    }
+
+    #[test]
+    fn assembler_parses_client_random() {
+        let metadata = metadata();
+        let mut assembler = Assembler::new(&metadata);
+        let record = assembler
+            .push(
+                &format!("CLIENT_RANDOM {} {}", CLIENT_RANDOM, "ab".repeat(48)),
+                &"test.keylog",
+                1,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!("example.com", record.get_metadata().sni);
+        assert_eq!(hex::decode(SERVER_RANDOM).unwrap(), record.get_metadata().server_random);
+        assert!(assembler.finish().is_empty());
+    }
+
+    #[test]
+    fn assembler_groups_tls13_labels() {
+        let metadata = metadata();
+        let mut assembler = Assembler::new(&metadata);
+        for label in [
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "EXPORTER_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+        ] {
+            assert!(assembler
+                .push(&format!("{} {} {}", label, CLIENT_RANDOM, "cd".repeat(32)), &"test.keylog", 1)
+                .unwrap()
+                .is_none());
+        }
+
+        let record = assembler
+            .push(
+                &format!("SERVER_TRAFFIC_SECRET_0 {} {}", CLIENT_RANDOM, "cd".repeat(32)),
+                &"test.keylog",
+                5,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(4, format_record(record.as_ref()).lines().count());
+        assert!(assembler.finish().is_empty());
+    }
+
+    #[test]
+    fn assembler_reports_incomplete_records() {
+        let metadata = metadata();
+        let mut assembler = Assembler::new(&metadata);
+        let line = format!("CLIENT_HANDSHAKE_TRAFFIC_SECRET {} {}", CLIENT_RANDOM, "cd".repeat(32));
+        assembler.push(&line, &"test.keylog", 3).unwrap();
+        let incomplete = assembler.finish();
+        assert_eq!(1, incomplete.len());
+        assert_eq!(hex::decode(CLIENT_RANDOM).unwrap(), incomplete[0].client_random);
+        assert_eq!(
+            vec![(String::from("test.keylog"), 3, line)],
+            incomplete[0]
+                .lines
+                .iter()
+                .map(|l| (l.file_name.clone(), l.line_num, l.line.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert!(assembler.finish().is_empty());
+    }
+
+    #[test]
+    fn assembler_expires_oldest_incomplete_records() {
+        let metadata = metadata();
+        let mut assembler = Assembler::new(&metadata);
+        for client_random in [CLIENT_RANDOM, SERVER_RANDOM] {
+            assembler
+                .push(
+                    &format!("CLIENT_HANDSHAKE_TRAFFIC_SECRET {} {}", client_random, "cd".repeat(32)),
+                    &"test.keylog",
+                    1,
+                )
+                .unwrap();
+        }
+
+        assert!(assembler.expire(Duration::from_secs(60), 2).is_empty());
+        let expired = assembler.expire(Duration::from_secs(60), 1);
+        assert_eq!(
+            vec![hex::decode(CLIENT_RANDOM).unwrap()],
+            expired.into_iter().map(|i| i.client_random).collect::<Vec<_>>()
+        );
+        assert_eq!(1, assembler.expire(Duration::ZERO, 2).len());
+        assert!(assembler.is_empty());
+    }
+
+    #[test]
+    fn assembler_keeps_lines_of_tls13_records_without_metadata() {
+        let metadata = Metadata::default();
+        let mut assembler = Assembler::new(&metadata);
+        let labels = [
+            "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+            "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+            "CLIENT_TRAFFIC_SECRET_0",
+            "SERVER_TRAFFIC_SECRET_0",
+        ];
+        for (index, label) in labels.iter().enumerate() {
+            let result = assembler.push(
+                &format!("{} {} {}", label, CLIENT_RANDOM, "cd".repeat(32)),
+                &"test.keylog",
+                index as u64 + 1,
+            );
+            assert_eq!(index == 3, result.is_err());
+        }
+
+        assert!(assembler.is_empty());
+        assert_eq!(
+            vec![1, 2, 3],
+            assembler.take_failed_lines().iter().map(|l| l.line_num).collect::<Vec<_>>()
+        );
+        assert!(assembler.take_failed_lines().is_empty());
+    }
+
+    #[test]
+    fn assembler_fails_on_missing_metadata() {
+        let metadata = Metadata::default();
+        let mut assembler = Assembler::new(&metadata);
+        assert!(assembler
+            .push(
+                &format!("CLIENT_RANDOM {} {}", CLIENT_RANDOM, "ab".repeat(48)),
+                &"test.keylog",
+                1
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+/// File name denoting the standard output.
+pub(crate) const STDOUT_PATH: &str = "-";
+
+/// Opens a file that is only accessible by its owner, since it is going to contain secrets.
+///
+/// An existing file that is accessible by others is restricted to its owner.
+pub(crate) fn create_private(path: &Path, append: bool) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true);
+    if append {
+        options.append(true);
+    } else {
+        options.write(true).truncate(true);
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let file = options
+        .open(path)
+        .with_context(|| format!("Failed to open output file {}", path.display()))?;
+    // The mode only applies to new files, so an existing file that others can access is restricted
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to get metadata of output file {}", path.display()))?;
+        if metadata.is_file() && metadata.permissions().mode() & 0o077 != 0 {
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to restrict permissions of output file {}", path.display()))?;
+        }
+    }
+
+    Ok(file)
+}
+
+/// Syncs a directory, so that the files renamed into it survive a crash.
+pub(crate) fn sync_directory(directory: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(directory)
+            .and_then(|d| d.sync_all())
+            .with_context(|| format!("Failed to sync directory {}", directory.display()))
+    }
+    #[cfg(not(unix))]
+    {
+        _ = directory;
+        Ok(())
+    }
+}
+
+/// Opens a buffered output, which is either a private file or the standard output for [`STDOUT_PATH`].
+pub(crate) fn open(path: &str) -> Result<Box<dyn Write>> {
+    open_with(path, false)
+}
+
+/// Opens a buffered output like [`open`], appending to the file instead of replacing its content.
+pub(crate) fn open_append(path: &str) -> Result<Box<dyn Write>> {
+    open_with(path, true)
+}
+
+fn open_with(path: &str, append: bool) -> Result<Box<dyn Write>> {
+    Ok(if path == STDOUT_PATH {
+        Box::new(BufWriter::new(std::io::stdout().lock()))
+    } else {
+        Box::new(BufWriter::new(create_private(Path::new(path), append)?))
+    })
+}
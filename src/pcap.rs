@@ -0,0 +1,448 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use time::OffsetDateTime;
+
+use crate::output;
+
+const PCAP_MICROS_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_NANOS_MAGIC: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_OBSOLETE_PACKET: u32 = 0x0000_0002;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+const PCAPNG_DECRYPTION_SECRETS: u32 = 0x0000_000a;
+const TLS_KEY_LOG_SECRETS: u32 = 0x544c_534b;
+const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+pub(crate) struct Packet {
+    pub timestamp: OffsetDateTime,
+    pub link_type: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reads packets from pcap and pcapng capture files.
+pub(crate) struct Reader {
+    input: BufReader<File>,
+    format: Format,
+}
+
+enum Format {
+    Pcap {
+        is_big_endian: bool,
+        ticks_per_second: u64,
+        link_type: u32,
+    },
+    Pcapng(Section),
+}
+
+#[derive(Default)]
+struct Section {
+    is_big_endian: bool,
+    interfaces: Vec<Interface>,
+}
+
+struct Interface {
+    link_type: u32,
+    ticks_per_second: u64,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open capture file {}", path.display()))?;
+        let mut input = BufReader::new(file);
+        let mut magic = [0u8; 4];
+        input
+            .read_exact(&mut magic)
+            .with_context(|| format!("Failed to read header of capture file {}", path.display()))?;
+        let format = if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut section = Section::default();
+            let block = read_block_after_type(&mut input, PCAPNG_SECTION_HEADER, &mut section.is_big_endian)?;
+            ensure!(block.is_some(), "Truncated section header in {}", path.display());
+            Format::Pcapng(section)
+        } else {
+            let (is_big_endian, ticks_per_second) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+                (PCAP_MICROS_MAGIC, _) => (false, 1_000_000),
+                (PCAP_NANOS_MAGIC, _) => (false, 1_000_000_000),
+                (_, PCAP_MICROS_MAGIC) => (true, 1_000_000),
+                (_, PCAP_NANOS_MAGIC) => (true, 1_000_000_000),
+                _ => bail!("Unsupported capture file format of {}", path.display()),
+            };
+            let mut header = [0u8; 20];
+            input
+                .read_exact(&mut header)
+                .with_context(|| format!("Failed to read header of capture file {}", path.display()))?;
+            Format::Pcap {
+                is_big_endian,
+                ticks_per_second,
+                link_type: read_u32(&header[16..], is_big_endian) & 0xffff,
+            }
+        };
+
+        Ok(Self { input, format })
+    }
+
+    fn read_pcap_packet(&mut self) -> Result<Option<Packet>> {
+        let (is_big_endian, ticks_per_second, link_type) = match self.format {
+            Format::Pcap {
+                is_big_endian,
+                ticks_per_second,
+                link_type,
+            } => (is_big_endian, ticks_per_second, link_type),
+            Format::Pcapng(_) => unreachable!(),
+        };
+        let mut header = [0u8; 16];
+        if !read_exact_or_eof(&mut self.input, &mut header)? {
+            return Ok(None);
+        }
+
+        let seconds = read_u32(&header[0..], is_big_endian) as u64;
+        let fraction = read_u32(&header[4..], is_big_endian) as u64;
+        let length = read_u32(&header[8..], is_big_endian) as usize;
+        ensure!(length <= MAX_BLOCK_SIZE, "Invalid packet length {}", length);
+        let mut data = vec![0u8; length];
+        self.input.read_exact(&mut data).context("Truncated packet")?;
+        let ticks = seconds
+            .checked_mul(ticks_per_second)
+            .and_then(|t| t.checked_add(fraction))
+            .context("Invalid packet timestamp")?;
+        Ok(Some(Packet {
+            timestamp: to_timestamp(ticks, ticks_per_second)?,
+            link_type,
+            data,
+        }))
+    }
+
+    fn read_pcapng_packet(&mut self) -> Result<Option<Packet>> {
+        let section = match &mut self.format {
+            Format::Pcapng(s) => s,
+            Format::Pcap { .. } => unreachable!(),
+        };
+        loop {
+            let (block_type, body) = match read_block(&mut self.input, &mut section.is_big_endian)? {
+                Some(b) => b,
+                None => return Ok(None),
+            };
+            let is_big_endian = section.is_big_endian;
+            match block_type {
+                PCAPNG_SECTION_HEADER => section.interfaces.clear(),
+                PCAPNG_INTERFACE_DESCRIPTION => {
+                    ensure!(body.len() >= 8, "Truncated interface description block");
+                    section.interfaces.push(Interface {
+                        link_type: read_u16(&body, is_big_endian) as u32,
+                        ticks_per_second: parse_resolution(&body[8..], is_big_endian),
+                    });
+                }
+                PCAPNG_ENHANCED_PACKET | PCAPNG_OBSOLETE_PACKET => {
+                    ensure!(body.len() >= 20, "Truncated packet block");
+                    let interface_id = if block_type == PCAPNG_ENHANCED_PACKET {
+                        read_u32(&body, is_big_endian) as usize
+                    } else {
+                        read_u16(&body, is_big_endian) as usize
+                    };
+                    let interface = section
+                        .interfaces
+                        .get(interface_id)
+                        .with_context(|| format!("Unknown interface {}", interface_id))?;
+                    let ticks = (read_u32(&body[4..], is_big_endian) as u64) << 32 | read_u32(&body[8..], is_big_endian) as u64;
+                    let length = (read_u32(&body[12..], is_big_endian) as usize).min(body.len() - 20);
+                    return Ok(Some(Packet {
+                        timestamp: to_timestamp(ticks, interface.ticks_per_second)?,
+                        link_type: interface.link_type,
+                        data: body[20..20 + length].to_vec(),
+                    }));
+                }
+                PCAPNG_SIMPLE_PACKET => {
+                    ensure!(body.len() >= 4, "Truncated simple packet block");
+                    let interface = section.interfaces.first().context("Missing interface for simple packet")?;
+                    let length = (read_u32(&body, is_big_endian) as usize).min(body.len() - 4);
+                    return Ok(Some(Packet {
+                        timestamp: OffsetDateTime::UNIX_EPOCH,
+                        link_type: interface.link_type,
+                        data: body[4..4 + length].to_vec(),
+                    }));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Iterator for Reader {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            Format::Pcap { .. } => self.read_pcap_packet(),
+            Format::Pcapng(_) => self.read_pcapng_packet(),
+        }
+        .transpose()
+    }
+}
+
+/// Copies a capture file as pcapng, embedding the key log into a Decryption Secrets Block
+/// that is placed right after the first section header.
+pub(crate) fn embed_secrets(input_path: &Path, output_path: &Path, key_log: &[u8]) -> Result<()> {
+    let file = File::open(input_path).with_context(|| format!("Failed to open capture file {}", input_path.display()))?;
+    let mut input = BufReader::new(file);
+    let mut output = BufWriter::new(output::create_private(output_path, false)?);
+    let mut magic = [0u8; 4];
+    input
+        .read_exact(&mut magic)
+        .with_context(|| format!("Failed to read header of capture file {}", input_path.display()))?;
+    if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+        let mut is_big_endian = false;
+        let mut block_type = PCAPNG_SECTION_HEADER;
+        let mut is_embedded = false;
+        loop {
+            let body = match read_block_after_type(&mut input, block_type, &mut is_big_endian)? {
+                Some(b) => b,
+                None => break,
+            };
+            write_block(&mut output, block_type, &body, is_big_endian)?;
+            if !is_embedded {
+                write_block(
+                    &mut output,
+                    PCAPNG_DECRYPTION_SECRETS,
+                    &secrets_body(key_log, is_big_endian),
+                    is_big_endian,
+                )?;
+                is_embedded = true;
+            }
+
+            let mut type_bytes = [0u8; 4];
+            if !read_exact_or_eof(&mut input, &mut type_bytes)? {
+                break;
+            }
+
+            block_type = read_u32(&type_bytes, is_big_endian);
+        }
+    } else {
+        let mut reader = Reader::open(input_path)?;
+        let (ticks_per_second, link_type) = match reader.format {
+            Format::Pcap {
+                ticks_per_second,
+                link_type,
+                ..
+            } => (ticks_per_second, link_type),
+            Format::Pcapng(_) => unreachable!(),
+        };
+        let mut header = Vec::new();
+        header.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&u64::MAX.to_le_bytes());
+        write_block(&mut output, PCAPNG_SECTION_HEADER, &header, false)?;
+        write_block(&mut output, PCAPNG_DECRYPTION_SECRETS, &secrets_body(key_log, false), false)?;
+
+        let mut interface = Vec::new();
+        interface.extend_from_slice(&(link_type as u16).to_le_bytes());
+        interface.extend_from_slice(&0u16.to_le_bytes());
+        interface.extend_from_slice(&0u32.to_le_bytes());
+        if ticks_per_second == 1_000_000_000 {
+            const IF_TSRESOL: u16 = 9;
+            interface.extend_from_slice(&IF_TSRESOL.to_le_bytes());
+            interface.extend_from_slice(&1u16.to_le_bytes());
+            interface.extend_from_slice(&[9, 0, 0, 0]);
+            interface.extend_from_slice(&[0, 0, 0, 0]);
+        }
+
+        write_block(&mut output, PCAPNG_INTERFACE_DESCRIPTION, &interface, false)?;
+        for packet in &mut reader {
+            let packet = packet?;
+            let ticks = (packet.timestamp.unix_timestamp_nanos() * ticks_per_second as i128 / 1_000_000_000) as u64;
+            let mut body = Vec::with_capacity(packet.data.len() + 24);
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body.extend_from_slice(&((ticks >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(ticks as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(packet.data.len() as u32).to_le_bytes());
+            body.extend_from_slice(&packet.data);
+            pad(&mut body);
+            write_block(&mut output, PCAPNG_ENHANCED_PACKET, &body, false)?;
+        }
+    }
+
+    output
+        .flush()
+        .with_context(|| format!("Failed to write capture file {}", output_path.display()))
+}
+
+fn secrets_body(key_log: &[u8], is_big_endian: bool) -> Vec<u8> {
+    let mut body = Vec::with_capacity(key_log.len() + 12);
+    body.extend_from_slice(&write_u32(TLS_KEY_LOG_SECRETS, is_big_endian));
+    body.extend_from_slice(&write_u32(key_log.len() as u32, is_big_endian));
+    body.extend_from_slice(key_log);
+    pad(&mut body);
+    body
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize((body.len() + 3) & !3, 0);
+}
+
+fn write_block(output: &mut impl Write, block_type: u32, body: &[u8], is_big_endian: bool) -> Result<()> {
+    let length = write_u32(body.len() as u32 + 12, is_big_endian);
+    output
+        .write_all(&write_u32(block_type, is_big_endian))
+        .and_then(|_| output.write_all(&length))
+        .and_then(|_| output.write_all(body))
+        .and_then(|_| output.write_all(&length))
+        .context("Failed to write capture block")
+}
+
+fn read_block(input: &mut impl Read, is_big_endian: &mut bool) -> Result<Option<(u32, Vec<u8>)>> {
+    let mut type_bytes = [0u8; 4];
+    if !read_exact_or_eof(input, &mut type_bytes)? {
+        return Ok(None);
+    }
+
+    // The section header block type is a palindrome, so it is recognized regardless of the byte order
+    let block_type = read_u32(&type_bytes, *is_big_endian);
+    Ok(read_block_after_type(input, block_type, is_big_endian)?.map(|b| (block_type, b)))
+}
+
+/// Reads the rest of a block after its type, updating the byte order when it is a section header.
+fn read_block_after_type(input: &mut impl Read, block_type: u32, is_big_endian: &mut bool) -> Result<Option<Vec<u8>>> {
+    let mut length_bytes = [0u8; 4];
+    if !read_exact_or_eof(input, &mut length_bytes)? {
+        return Ok(None);
+    }
+
+    let mut prefix = Vec::new();
+    if block_type == PCAPNG_SECTION_HEADER {
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic).context("Truncated section header")?;
+        *is_big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAPNG_BYTE_ORDER_MAGIC, _) => false,
+            (_, PCAPNG_BYTE_ORDER_MAGIC) => true,
+            _ => bail!("Invalid section header byte order magic"),
+        };
+        prefix.extend_from_slice(&magic);
+    }
+
+    let length = read_u32(&length_bytes, *is_big_endian) as usize;
+    ensure!(
+        (12 + prefix.len()..=MAX_BLOCK_SIZE).contains(&length) && length.is_multiple_of(4),
+        "Invalid block length {}",
+        length
+    );
+    let mut body = prefix;
+    let prefix_length = body.len();
+    body.resize(length - 8, 0);
+    input.read_exact(&mut body[prefix_length..]).context("Truncated block")?;
+    let trailer = body.split_off(length - 12);
+    ensure!(trailer == length_bytes, "Mismatching block length");
+    Ok(Some(body))
+}
+
+/// Returns the timestamp ticks per second from the `if_tsresol` interface option.
+fn parse_resolution(mut options: &[u8], is_big_endian: bool) -> u64 {
+    const IF_TSRESOL: u16 = 9;
+    while options.len() >= 4 {
+        let code = read_u16(options, is_big_endian);
+        let length = read_u16(&options[2..], is_big_endian) as usize;
+        let value = &options[4..];
+        if code == 0 || value.len() < length {
+            break;
+        }
+
+        if code == IF_TSRESOL && length == 1 {
+            let exponent = (value[0] & 0x7f) as u32;
+            return if value[0] & 0x80 == 0 {
+                10u64.checked_pow(exponent).unwrap_or(1_000_000)
+            } else {
+                2u64.checked_pow(exponent).unwrap_or(1_000_000)
+            };
+        }
+
+        options = &value[((length + 3) & !3).min(value.len())..];
+    }
+
+    1_000_000
+}
+
+fn to_timestamp(ticks: u64, ticks_per_second: u64) -> Result<OffsetDateTime> {
+    let nanos = ticks as i128 * 1_000_000_000 / ticks_per_second as i128;
+    OffsetDateTime::from_unix_timestamp_nanos(nanos).context("Invalid packet timestamp")
+}
+
+fn read_exact_or_eof(input: &mut impl Read, buffer: &mut [u8]) -> Result<bool> {
+    match input.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e).context("Failed to read capture file"),
+    }
+}
+
+fn read_u16(bytes: &[u8], is_big_endian: bool) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    if is_big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], is_big_endian: bool) -> u32 {
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    if is_big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn write_u32(value: u32, is_big_endian: bool) -> [u8; 4] {
+    if is_big_endian {
+        value.to_be_bytes()
+    } else {
+        value.to_le_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn embed_secrets_converts_pcap_to_pcapng() {
+        let directory = std::env::temp_dir();
+        let input_path = directory.join(format!("sslkeylog-processor-{}.pcap", std::process::id()));
+        let output_path = directory.join(format!("sslkeylog-processor-{}.pcapng", std::process::id()));
+        let mut pcap = Vec::new();
+        for value in [PCAP_NANOS_MAGIC, 0x0002_0004, 0, 0, 65535, 1] {
+            pcap.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for value in [1_600_000_000, 123, 3, 3] {
+            pcap.extend_from_slice(&u32::to_be_bytes(value));
+        }
+
+        pcap.extend_from_slice(b"abc");
+        std::fs::write(&input_path, pcap).unwrap();
+        embed_secrets(&input_path, &output_path, b"CLIENT_RANDOM 00 11\n").unwrap();
+
+        let mut input = BufReader::new(File::open(&output_path).unwrap());
+        let mut is_big_endian = false;
+        let mut blocks = Vec::new();
+        while let Some((block_type, body)) = read_block(&mut input, &mut is_big_endian).unwrap() {
+            blocks.push((block_type, body));
+        }
+
+        let packets = Reader::open(&output_path).unwrap().collect::<Result<Vec<_>>>().unwrap();
+        std::fs::remove_file(&input_path).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+        assert_eq!(PCAPNG_DECRYPTION_SECRETS, blocks[1].0);
+        assert_eq!(b"CLIENT_RANDOM 00 11\n", &blocks[1].1[8..28]);
+        assert_eq!(1, packets.len());
+        assert_eq!(b"abc", packets[0].data.as_slice());
+        assert_eq!(1_600_000_000_000_000_123, packets[0].timestamp.unix_timestamp_nanos());
+    }
+}
@@ -1,12 +1,90 @@
-use std::sync::{atomic::AtomicBool, Arc};
+use std::{
+    path::Path,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use anyhow::Result;
 
-use crate::{configuration, processor, storage};
+use crate::{
+    capture, checkpoint,
+    configuration::{self, Mode},
+    discovery, logging, lookup, metrics, nss, processor, redaction, reject, spool, storage, syslog,
+};
 
 pub(crate) fn process(args: &configuration::Configuration, term_token: &Arc<AtomicBool>) -> Result<()> {
-    let db = mongodb::sync::Client::with_options(args.options.clone())?.database(&args.db_name);
-    let mut store = storage::Store::new(&db);
-    let mut context = processor::Processor::new(args.filter.as_ref(), term_token, &mut store, args.input_format);
-    context.process(&args.files)
+    redaction::set_show_raw(args.debug_raw_lines);
+    let mut store = args.storage.as_ref().map(storage::open).transpose()?;
+    match (&args.mode, store.as_mut()) {
+        (Mode::Process, _) => {}
+        (Mode::Lookup(options), Some(store)) => return lookup::lookup(options, store.as_mut()),
+        (Mode::Capture(options), Some(store)) => return capture::capture(options, store.as_mut()),
+        (_, None) => unreachable!("storage is only missing in dry-run mode"),
+    }
+
+    if let Some(address) = args.metrics_listen {
+        metrics::serve(address)?;
+    }
+
+    let files = discovery::expand(&args.files, &args.discovery)?;
+    if files.is_empty() && args.syslog.is_none() && args.spool.is_none() {
+        logging::print_warning("No input files found");
+    }
+
+    let nss_metadata = args
+        .nss_metadata
+        .as_ref()
+        .map(nss::Metadata::load)
+        .transpose()?
+        .unwrap_or_default();
+    let mut state = args.state_file.as_ref().map(checkpoint::State::load).transpose()?;
+    let store: Option<&mut dyn storage::Storage> = match &mut store {
+        Some(s) => Some(s.as_mut()),
+        None => None,
+    };
+    let mut context = processor::Processor::new(
+        args.filter.as_ref(),
+        term_token,
+        store,
+        args.input_format,
+        &args.journal_filter,
+        &nss_metadata,
+        state.as_mut(),
+    );
+    context.limit_batches(args.batching.clone());
+    if let Some(path) = &args.reject_file {
+        context.reject_to(reject::RejectFile::open(Path::new(path))?);
+    }
+
+    let result = if let Some(options) = &args.syslog {
+        syslog::Listener::start(options).and_then(|l| context.receive(&l, args.flush_interval))
+    } else if let Some(directory) = &args.spool {
+        spool::Watcher::start(Path::new(directory)).and_then(|mut w| context.watch(&mut w, args.flush_interval))
+    } else if args.follow {
+        context.follow(&files, args.flush_interval)
+    } else {
+        context.process(&files, &args.completion)
+    };
+
+    let summary = context.summary();
+    if args.dry_run {
+        for (file_name, file_stats) in context.stats() {
+            println!("{}: {}", file_name, file_stats);
+        }
+    } else {
+        logging::print_info(&format!("Summary: {}", summary));
+    }
+
+    let outputs = [
+        args.report.as_ref().map(|r| summary.write_report(r)),
+        args.metrics_textfile.as_ref().map(|f| metrics::write_textfile(f)),
+    ];
+    for e in outputs.into_iter().flatten().filter_map(Result::err) {
+        if result.is_ok() {
+            return Err(e);
+        }
+
+        logging::print(&e);
+    }
+
+    result
 }
@@ -2,57 +2,171 @@
     collections::{hash_map::DefaultHasher, HashMap, HashSet},
     convert::TryFrom,
     hash::{Hash, Hasher},
-    io::BufRead,
     path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Instant,
 };
 
-use anyhow::{bail, Context, Result};
-use mongodb::bson;
+use anyhow::{anyhow, bail, Context, Result};
 use regex::Regex;
-use time::{format_description::FormatItem, macros::format_description, Duration};
+use time::Duration;
 
-use crate::{data_model::*, errors, logging, storage::Store};
+use crate::{
+    checkpoint, completion,
+    data_model::*,
+    errors, follow,
+    input::{self, FileIdentity, LineSource, Position},
+    journal,
+    logging::{self, Level},
+    metrics::{self, ParseError},
+    nss, reject, spool,
+    stats::{self, FileStats, Summary, WriteStats},
+    storage::Storage,
+    syslog,
+};
 
 pub(crate) struct Processor<'a> {
     filter: Option<&'a Regex>,
     term_token: &'a Arc<AtomicBool>,
-    store: &'a mut Store<'a>,
+    store: Option<&'a mut dyn Storage>,
     input_format: InputFormat,
+    /// Format of the lines being processed, detected per file for the auto input format.
+    line_format: InputFormat,
+    journal_filter: &'a journal::Filter,
+    nss: nss::Assembler<'a>,
+    stats: Vec<(String, FileStats)>,
+    /// Index in `stats` of the file whose lines are being processed.
+    current_stats: Option<usize>,
+    writes: WriteStats,
+    batch_map: HashMap<CollectionName, Batch>,
+    next_collection_names: HashSet<CollectionName>,
+    state: Option<&'a mut checkpoint::State>,
+    sources: Vec<Source>,
+    current: Option<(usize, Position)>,
+    nss_hold: Option<(usize, Position)>,
+    rejects: Option<reject::RejectFile>,
+    policy: BatchPolicy,
+    /// Records in all batches of `batch_map`.
+    pending_records: usize,
+    /// Sequence number of the last record added to a batch, for the least recently used order.
+    record_num: u64,
+    last_expiry_check: Instant,
+}
+
+/// Limits of the records pending in batches, a batch is written to the storage when any limit is reached.
+#[derive(Debug, Clone)]
+pub(crate) struct BatchPolicy {
+    /// Records per batch.
+    pub size: usize,
+    /// Age of a batch since its first record.
+    pub max_age: Option<std::time::Duration>,
+    /// Records in all batches, the least recently used batches are written beyond it.
+    pub max_pending_records: usize,
+    /// Batches at once, the least recently used batch is written beyond it.
+    pub max_pending_collections: usize,
+    /// Writes all batches after every file, so that nothing is pending across files.
+    pub flush_per_file: bool,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        Self {
+            size: 1000,
+            max_age: None,
+            max_pending_records: 100_000,
+            max_pending_collections: 1000,
+            flush_per_file: false,
+        }
+    }
 }
 
 impl<'a> Processor<'a> {
     pub fn new(
         filter: Option<&'a Regex>,
         term_token: &'a Arc<AtomicBool>,
-        store: &'a mut Store<'a>,
+        store: Option<&'a mut dyn Storage>,
         input_format: InputFormat,
+        journal_filter: &'a journal::Filter,
+        nss_metadata: &'a nss::Metadata,
+        state: Option<&'a mut checkpoint::State>,
     ) -> Self {
         Self {
             filter,
             term_token,
             store,
             input_format,
+            line_format: input_format,
+            journal_filter,
+            nss: nss::Assembler::new(nss_metadata),
+            stats: Vec::new(),
+            current_stats: None,
+            writes: WriteStats::default(),
+            batch_map: HashMap::new(),
+            next_collection_names: HashSet::new(),
+            state,
+            sources: Vec::new(),
+            current: None,
+            nss_hold: None,
+            rejects: None,
+            policy: BatchPolicy::default(),
+            pending_records: 0,
+            record_num: 0,
+            last_expiry_check: Instant::now(),
         }
     }
 
-    pub fn process<Paths>(&mut self, paths: Paths) -> Result<()>
+    pub fn limit_batches(&mut self, policy: BatchPolicy) {
+        self.policy = policy;
+    }
+
+    /// Appends the lines that fail to be parsed to the reject file from now on.
+    pub fn reject_to(&mut self, rejects: reject::RejectFile) {
+        self.rejects = Some(rejects);
+    }
+
+    /// Returns the counters of every input file, in the order the files were first read.
+    pub fn stats(&self) -> &[(String, FileStats)] {
+        &self.stats
+    }
+
+    /// Returns the counters of the whole run, including the records written to the storage.
+    pub fn summary(&self) -> Summary {
+        Summary::new(self.stats.iter().map(|(_, s)| s), &self.writes)
+    }
+
+    /// Processes the files in order, applying the completion actions to every file once its records are stored.
+    pub fn process<Paths>(&mut self, paths: Paths, actions: &completion::Actions) -> Result<()>
     where
         Paths: IntoIterator,
         Paths::Item: AsRef<str>,
     {
         let mut failure = None;
-        let mut batch_map = HashMap::<String, Vec<bson::Document>>::new();
-        let mut next_collection_names = HashSet::new();
         for path in paths {
             if self.term_token.load(Ordering::Relaxed) {
                 bail!(errors::TerminatedError::new("path iteration"));
             }
 
-            if let Err(f) = self.process_file(&PathBuf::from(path.as_ref()), &mut batch_map, &mut next_collection_names) {
+            let path = PathBuf::from(path.as_ref());
+            let result = if !actions.is_empty() && path != std::path::Path::new(input::STDIN_PATH) {
+                self.process_and_complete(&path, actions)
+            } else if self.policy.flush_per_file {
+                self.process_and_flush(&path)
+            } else {
+                self.process_file(&path)
+            };
+            // Checkpoints are saved at file boundaries and flushes rather than after every batch, since saving syncs the state file
+            let result = match (result, self.save_checkpoints()) {
+                (Ok(()), saved) => saved,
+                (Err(f), Ok(())) => Err(f),
+                (Err(f), Err(e)) => {
+                    logging::print(&e);
+                    Err(f)
+                }
+            };
+            if let Err(f) = result {
                 logging::print(&f);
                 if failure.is_none() {
                     failure = Some(f);
@@ -60,172 +174,1287 @@ pub fn process<Paths>(&mut self, paths: Paths) -> Result<()>
             }
         }
 
-        for (collection_name, batch) in batch_map {
+        self.flush(true)?;
+        failure.map(|f| bail!(f.context("Failed to process files"))).unwrap_or(Ok(()))
+    }
+
+    fn process_and_complete(&mut self, path: &std::path::Path, actions: &completion::Actions) -> Result<()> {
+        let result = self.process_and_flush(path);
+        if result.as_ref().is_err_and(|f| f.is::<errors::TerminatedError>()) {
+            return result;
+        }
+
+        // Only the batches of this file are left after a failed flush, they are not retried with the next file
+        // so that its outcome only depends on its own records
+        self.drop_pending();
+        match actions.apply(path, result.is_ok()) {
+            Ok(Some(action)) => logging::print_info(&format!("{}: {}", path.display(), action)),
+            Ok(None) => {}
+            Err(f) if result.is_ok() => return Err(f),
+            Err(f) => logging::print(&f),
+        }
+
+        result
+    }
+
+    /// Processes a file and flushes its records, so that the file can be acted upon once they are stored.
+    fn process_and_flush(&mut self, path: &std::path::Path) -> Result<()> {
+        let result = self.process_file(path);
+        let flushed = self.flush(false);
+        match (result, flushed) {
+            (Ok(()), flushed) => flushed.with_context(|| format!("Failed to store records of {}", path.display())),
+            (Err(f), Ok(())) => Err(f),
+            (Err(f), Err(e)) => {
+                logging::print(&e);
+                Err(f)
+            }
+        }
+    }
+
+    /// Processes the files continuously like `tail -F`, reopening them on rotation or truncation
+    /// and flushing the pending batches at least once per `flush_interval`, until terminated.
+    ///
+    /// Files are resumed from their checkpoints, those without one are followed from their end.
+    pub fn follow<Paths>(&mut self, paths: Paths, flush_interval: std::time::Duration) -> Result<()>
+    where
+        Paths: IntoIterator,
+        Paths::Item: AsRef<str>,
+    {
+        // The lines of a TLS 1.3 session are written within moments of each other, older ones were lost
+        const NSS_MAX_AGE: std::time::Duration = std::time::Duration::from_secs(300);
+        const NSS_MAX_PENDING: usize = 10_000;
+        let mut files: Vec<_> = paths
+            .into_iter()
+            .map(|p| {
+                let path = PathBuf::from(p.as_ref());
+                match self.state.as_ref().and_then(|s| s.entry(&path.display().to_string())) {
+                    Some((identity, position)) => follow::FollowedFile::resume(path, identity, position),
+                    None => follow::FollowedFile::new(path),
+                }
+            })
+            .collect();
+        // Source of the lines of every file, replaced when the file is rotated
+        let mut sources: Vec<Option<usize>> = vec![None; files.len()];
+        let mut last_flush = Instant::now();
+        loop {
             if self.term_token.load(Ordering::Relaxed) {
-                bail!(errors::TerminatedError::new("flushing"));
+                self.flush(false)?;
+                bail!(errors::TerminatedError::new("following"));
             }
 
-            let count = batch.len();
-            println!("flushing {} to {}", count, collection_name);
-            self.store
-                .write(&collection_name, batch)
-                .with_context(|| format!("Failed to flush {} to {}", count, collection_name))?;
+            let mut is_idle = true;
+            for (file, source) in files.iter_mut().zip(&mut sources) {
+                let lines = match file.poll() {
+                    Ok(lines) => lines,
+                    Err(f) => {
+                        logging::print(&f);
+                        continue;
+                    }
+                };
+
+                if !lines.is_empty() {
+                    self.select_file(&file.name().to_string());
+                }
+
+                for line in lines {
+                    is_idle = false;
+                    if self.state.is_some() {
+                        *source = match *source {
+                            Some(s) if self.sources[s].identity == line.identity => Some(s),
+                            _ => Some(self.add_followed_source(file.name().to_string(), line.identity)),
+                        };
+                    }
+
+                    let location = FileLocation {
+                        file_name: &file.name(),
+                        line_num: line.end.line_num,
+                    };
+                    if let Err(f) = self.process_source_line(&location, line.line, *source, line.start, line.end) {
+                        logging::print_with(&f, &location.fields());
+                    }
+                }
+
+                self.current = None;
+            }
+
+            let expired = self.nss.expire(NSS_MAX_AGE, NSS_MAX_PENDING);
+            if let Err(f) = self.reject_incomplete(expired) {
+                logging::print(&f);
+            }
+
+            if last_flush.elapsed() >= flush_interval {
+                // Batches that failed to be written stay pending and are retried at the next interval
+                if let Err(f) = self.flush(false) {
+                    logging::print(&f);
+                }
+                last_flush = Instant::now();
+            }
+
+            if is_idle {
+                const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Processes the messages of a syslog listener until terminated,
+    /// flushing the pending batches at least once per `flush_interval`.
+    pub fn receive(&mut self, listener: &syslog::Listener, flush_interval: std::time::Duration) -> Result<()> {
+        const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+        let mut message_num = 0;
+        let mut last_flush = Instant::now();
+        loop {
+            if self.term_token.load(Ordering::Relaxed) {
+                // Messages that were already received are processed before the final flush
+                while let Some(message) = listener.try_receive() {
+                    message_num += 1;
+                    self.process_message(message, message_num);
+                }
+
+                self.flush(false)?;
+                bail!(errors::TerminatedError::new("receiving"));
+            }
+
+            if let Some(message) = listener.receive(POLL_INTERVAL)? {
+                message_num += 1;
+                self.process_message(message, message_num);
+            }
+
+            if last_flush.elapsed() >= flush_interval {
+                if let Err(f) = self.flush(false) {
+                    logging::print(&f);
+                }
+                last_flush = Instant::now();
+            }
         }
+    }
+
+    /// Processes the files of a spool directory as they arrive until terminated, starting with those already there.
+    ///
+    /// Every file is flushed once processed, then moved to `done` or `failed` depending on whether any line failed to be parsed.
+    /// Files whose records failed to be stored are left in place and processed again every `retry_interval`.
+    pub fn watch(&mut self, watcher: &mut spool::Watcher, retry_interval: std::time::Duration) -> Result<()> {
+        const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+        let mut files = watcher.scan()?;
+        let mut retries = Vec::new();
+        let mut last_retry = Instant::now();
+        loop {
+            if !retries.is_empty() && last_retry.elapsed() >= retry_interval {
+                for path in std::mem::take(&mut retries) {
+                    if !files.contains(&path) {
+                        files.push(path);
+                    }
+                }
+                last_retry = Instant::now();
+            }
+
+            for path in files {
+                if self.term_token.load(Ordering::Relaxed) {
+                    bail!(errors::TerminatedError::new("watching"));
+                }
+
+                if !self.process_spooled(watcher, &path)? && !retries.contains(&path) {
+                    retries.push(path);
+                }
+            }
 
-        for collection_name in next_collection_names {
             if self.term_token.load(Ordering::Relaxed) {
+                bail!(errors::TerminatedError::new("watching"));
+            }
+
+            files = watcher.poll()?;
+            if files.is_empty() {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Processes a spooled file, returning `false` if it is left in place to be retried because its records failed to be stored.
+    ///
+    /// Only fails on termination, in which case the file is left for the next run.
+    fn process_spooled(&mut self, watcher: &spool::Watcher, path: &std::path::Path) -> Result<bool> {
+        // Files may be reported again after being moved, e.g. when found by the startup scan
+        if !path.is_file() {
+            return Ok(true);
+        }
+
+        let write_errors = self.writes.errors;
+        let is_success = match self.process_and_flush(path) {
+            Ok(()) => true,
+            Err(f) if f.is::<errors::TerminatedError>() => return Err(f),
+            Err(f) => {
+                logging::print(&f);
+                false
+            }
+        };
+        if self.writes.errors != write_errors {
+            // The whole file is processed again, so its records that are still pending are dropped
+            self.drop_pending();
+            logging::print_warning(&format!("{}: failed to store records, leaving it to retry", path.display()));
+            return Ok(false);
+        }
+
+        match watcher.complete(path, is_success) {
+            Ok(target) => logging::print_info(&format!("{}: moved to {}", path.display(), target.display())),
+            Err(f) => logging::print(&f),
+        }
+
+        Ok(true)
+    }
+
+    fn process_message(&mut self, message: syslog::Message, message_num: u64) {
+        // Keyed by address only, so that the per-file counters do not grow with every TCP connection
+        let file_name = format!("syslog {}", message.peer.ip());
+        self.select_file(&file_name);
+        let location = FileLocation {
+            file_name: &file_name,
+            line_num: message_num,
+        };
+        if let Err(f) = self.process_line(&location, message.line) {
+            logging::print_with(&f, &location.fields());
+        }
+    }
+
+    /// Writes all pending batches, those that fail to be written are kept pending for the next flush,
+    /// so that they are retried and the checkpoints of their sources do not advance past them.
+    fn flush(&mut self, is_interruptible: bool) -> Result<()> {
+        if self.store.is_none() {
+            return Ok(());
+        }
+
+        let mut failure = None;
+        let mut is_terminated = false;
+        for (collection_name, batch) in std::mem::take(&mut self.batch_map) {
+            is_terminated = is_terminated || (is_interruptible && self.term_token.load(Ordering::Relaxed));
+            if is_terminated {
+                self.batch_map.insert(collection_name, batch);
+                continue;
+            }
+
+            let count = batch.records.len();
+            logging::log(
+                Level::Debug,
+                &format!("flushing {} to {}", count, collection_name),
+                &[("collection", &collection_name)],
+            );
+            let store = self.store.as_mut().unwrap();
+            if let Err(e) = write_batch(&mut **store, &mut self.writes, &collection_name, &batch.records) {
+                let f = e.context(format!("Failed to flush {} to {}", count, collection_name));
+                match failure {
+                    None => failure = Some(f),
+                    Some(_) => logging::print_with(&f, &[("collection", &collection_name)]),
+                }
+                self.batch_map.insert(collection_name, batch);
+            }
+        }
+
+        self.pending_records = self.batch_map.values().map(|b| b.records.len()).sum();
+        self.update_pending();
+        if is_terminated {
+            bail!(errors::TerminatedError::new("flushing"));
+        }
+
+        for collection_name in std::mem::take(&mut self.next_collection_names) {
+            if is_interruptible && self.term_token.load(Ordering::Relaxed) {
                 bail!(errors::TerminatedError::new("ensuring"));
             }
 
-            println!("ensuring {}", collection_name);
-            self.store.ensure_collection(&collection_name);
+            logging::log(
+                Level::Debug,
+                &format!("ensuring {}", collection_name),
+                &[("collection", &collection_name)],
+            );
+            if let Err(f) = self.store.as_mut().unwrap().ensure_collection(&collection_name) {
+                logging::print_with(
+                    &f.context(format!("Failed to ensure {}", collection_name)),
+                    &[("collection", &collection_name)],
+                );
+            }
         }
 
-        failure.map(|f| bail!(f.context("Failed to process files"))).unwrap_or(Ok(()))
+        self.save_checkpoints()?;
+        failure.map(Err).unwrap_or(Ok(()))
     }
 
-    fn process_file(
-        &mut self,
-        path: &std::path::Path,
-        batch_map: &mut HashMap<String, Vec<bson::Document>>,
-        next_collection_names: &mut HashSet<String>,
-    ) -> Result<()> {
-        let file_name = &path.display();
+    /// Drops the pending batches, holding the checkpoints of their sources before their records.
+    fn drop_pending(&mut self) {
+        for batch in std::mem::take(&mut self.batch_map).into_values() {
+            self.hold(&batch.starts);
+        }
+
+        self.pending_records = 0;
+        self.update_pending();
+    }
+
+    /// Keeps the checkpoints of the sources before the starts of records that were not stored.
+    fn hold(&mut self, starts: &HashMap<usize, Position>) {
+        for (&source, &start) in starts {
+            let hold = &mut self.sources[source].hold;
+            *hold = Some(hold.map_or(start, |h| h.min(start)));
+        }
+    }
+
+    /// Records the positions up to which every source has been durably stored.
+    ///
+    /// A source position never advances past the start of a line whose record is still pending
+    /// in a batch, failed to be written, or belongs to an incomplete NSS record.
+    fn save_checkpoints(&mut self) -> Result<()> {
+        let state = match self.state.as_mut() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        let mut pending = HashMap::<usize, Position>::new();
+        let starts = self
+            .batch_map
+            .values()
+            .flat_map(|b| b.starts.iter())
+            .chain(self.nss_hold.as_ref().map(|(s, p)| (s, p)));
+        for (&source, &start) in starts {
+            pending.entry(source).and_modify(|p| *p = (*p).min(start)).or_insert(start);
+        }
+
+        for (index, source) in self.sources.iter().enumerate() {
+            let position = [Some(source.position), source.hold, pending.get(&index).copied()]
+                .into_iter()
+                .flatten()
+                .min()
+                .unwrap();
+            state.set(&source.name, source.identity, position);
+        }
+
+        state.save()
+    }
+
+    fn process_file(&mut self, path: &std::path::Path) -> Result<()> {
+        let file_name: &dyn std::fmt::Display = if path == std::path::Path::new(input::STDIN_PATH) {
+            &"stdin"
+        } else {
+            &path.display()
+        };
+
+        logging::log(Level::Trace, &format!("{}: open", file_name), &[("file", file_name)]);
+        self.select_file(&file_name.to_string());
+        let source = if self.state.is_some() && path != std::path::Path::new(input::STDIN_PATH) {
+            Some(self.add_source(path)?)
+        } else {
+            None
+        };
+        let mut lines = self.open_lines(path)?;
+        if let Some(index) = source {
+            let position = self.sources[index].position;
+            if position.offset != 0 {
+                logging::print_info(&format!("{}: resuming at line {}", file_name, position.line_num + 1));
+                let is_reached = lines
+                    .skip_to(position)
+                    .with_context(|| format!("Failed to skip to checkpoint of {}", file_name))?;
+                if !is_reached {
+                    // Truncated in place, e.g. by `copytruncate` log rotation
+                    logging::print_warning(&format!(
+                        "{}: file is shorter than its checkpoint, processing it from the start",
+                        file_name
+                    ));
+                    self.sources[index].position = Position::default();
+                    lines = self.open_lines(path)?;
+                }
+            }
+        }
+
+        if let InputFormat::Auto = self.input_format {
+            lines = self.detect_format(lines, file_name)?;
+        }
 
-        // println!("{}: open", file_name);
-        let file = std::fs::File::open(path).with_context(|| format!("Failed to open file {}", file_name))?;
-        let lines = std::io::BufReader::new(file).lines();
-        self.process_lines(lines, file_name, batch_map, next_collection_names)?;
-        // println!("{}: done", file_name);
+        self.process_lines(lines, file_name, source)?;
+        logging::log(Level::Trace, &format!("{}: done", file_name), &[("file", file_name)]);
         Ok(())
     }
 
-    fn process_lines<Lines, Line, Error>(
+    fn open_lines(&self, path: &std::path::Path) -> Result<Box<dyn LineSource + 'a>> {
+        let reader = input::open(path)?;
+        Ok(match self.input_format {
+            InputFormat::Journal => Box::new(journal::Messages::new(reader, self.journal_filter)),
+            _ => Box::new(input::Lines::new(reader)),
+        })
+    }
+
+    /// Locks in the format of a file from its first lines, returning a source that still yields them.
+    fn detect_format<'l>(
         &mut self,
-        lines: Lines,
-        file_name: &impl std::fmt::Display,
-        batch_map: &mut HashMap<String, Vec<bson::Document>>,
-        next_collection_names: &mut HashSet<String>,
-    ) -> Result<()>
-    where
-        Lines: IntoIterator<Item = Result<Line, Error>>,
-        Line: AsRef<str>,
-        Error: std::error::Error + Send + Sync + 'static,
-    {
-        let mut line_num = 0u64;
+        lines: Box<dyn LineSource + 'l>,
+        file_name: &dyn std::fmt::Display,
+    ) -> Result<Box<dyn LineSource + 'l>> {
+        const DETECTION_LINE_COUNT: usize = 20;
+        let mut lines = input::Peekable::new(lines);
+        let sample: Vec<_> = lines
+            .peek(DETECTION_LINE_COUNT)
+            .filter_map(|l| l.as_ref().ok())
+            .map(String::as_str)
+            .collect();
+        if !sample.is_empty() {
+            self.line_format = detect_input_format(sample).with_context(|| format!("Failed to detect format of {}", file_name))?;
+            logging::log(
+                Level::Debug,
+                &format!("{}: detected {} format", file_name, self.line_format),
+                &[("file", file_name)],
+            );
+        }
+
+        Ok(Box::new(lines))
+    }
+
+    fn add_source(&mut self, path: &std::path::Path) -> Result<usize> {
+        let metadata = std::fs::metadata(path).with_context(|| format!("Failed to get metadata of file {}", path.display()))?;
+        let name = path.display().to_string();
+        let identity = FileIdentity::of(&metadata);
+        let position = self.state.as_ref().and_then(|s| s.get(&name, identity)).unwrap_or_default();
+        self.sources.push(Source {
+            name,
+            identity,
+            position,
+            hold: None,
+        });
+        Ok(self.sources.len() - 1)
+    }
+
+    /// Adds the source of a followed file, whose position is only recorded once its first line is processed.
+    fn add_followed_source(&mut self, name: String, identity: Option<FileIdentity>) -> usize {
+        self.sources.push(Source {
+            name,
+            identity,
+            position: Position::default(),
+            hold: None,
+        });
+        self.sources.len() - 1
+    }
+
+    fn process_lines(
+        &mut self,
+        mut lines: Box<dyn LineSource + '_>,
+        file_name: &dyn std::fmt::Display,
+        source: Option<usize>,
+    ) -> Result<()> {
         let mut failure = None;
-        #[allow(clippy::explicit_counter_loop)]
-        for line in lines {
-            line_num += 1;
-            let location = FileLocation { file_name, line_num };
+        loop {
+            let start = lines.current_position();
+            let line = match lines.next() {
+                Some(l) => l,
+                None => break,
+            };
+            let location = FileLocation {
+                file_name,
+                line_num: lines.current_position().line_num,
+            };
 
             if self.term_token.load(Ordering::Relaxed) {
                 bail!(errors::TerminatedError::new(format!("processing {}", location)));
             }
 
-            match self.process_line(&location, line, batch_map) {
-                Ok(Some(n)) => {
-                    next_collection_names.insert(n);
-                }
-                Ok(_) => {}
-                Err(f) => {
-                    logging::print(&f);
-                    if failure.is_none() {
-                        failure = Some(f);
-                    }
+            let end = lines.current_position();
+            if let Err(f) = self.process_source_line(&location, line, source, start, end) {
+                logging::print_with(&f, &location.fields());
+                if failure.is_none() {
+                    failure = Some(f);
                 }
             }
         }
 
+        self.current = None;
+        self.nss_hold = None;
+        let incomplete = self.nss.finish();
+        if let Err(f) = self.reject_incomplete(incomplete) {
+            logging::print(&f);
+            if failure.is_none() {
+                failure = Some(f);
+            }
+        }
+
         failure
             .map(|f| bail!(f.context(format!("Failed to process lines of {}", file_name))))
             .unwrap_or(Ok(()))
     }
 
+    /// Processes a line between two positions of a source, so that checkpoints do not advance past its record until it is stored.
+    fn process_source_line<Line: AsRef<str>, Error: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        location: &FileLocation,
+        line: Result<Line, Error>,
+        source: Option<usize>,
+        start: Position,
+        end: Position,
+    ) -> Result<()> {
+        self.current = source.map(|s| match self.nss_hold {
+            Some((hold_source, hold)) if hold_source == s => (s, hold.min(start)),
+            _ => (s, start),
+        });
+        let result = self.process_line(location, line);
+        if let Some(s) = source {
+            self.sources[s].position = end;
+            if self.nss.is_empty() {
+                self.nss_hold = None;
+            } else if self.nss_hold.is_none() {
+                self.nss_hold = Some((s, start));
+            }
+        }
+
+        result
+    }
+
     fn process_line<Line: AsRef<str>, Error: std::error::Error + Send + Sync + 'static>(
         &mut self,
         location: &FileLocation,
         line: Result<Line, Error>,
-        batch_map: &mut HashMap<String, Vec<bson::Document>>,
-    ) -> Result<Option<String>> {
+    ) -> Result<()> {
+        let sni_warnings = stats::sni_warnings();
+        let reason = match (&line, self.line_format) {
+            (Err(_), _) => ParseError::Read,
+            (Ok(_), InputFormat::Nss) => ParseError::Nss,
+            (Ok(_), _) => ParseError::Format,
+        };
+        let raw_line = match &line {
+            _ if self.rejects.is_none() => None,
+            Ok(l) => Some(l.as_ref().as_bytes().to_vec()),
+            Err(e) => reject::invalid_line(e),
+        };
+        let record = self.parse_line(location, line);
+        let file_stats = self.file_stats();
+        file_stats.lines += 1;
+        metrics::count_line();
+        file_stats.sni_warnings += stats::sni_warnings() - sni_warnings;
+        match record {
+            Ok(Some(record)) => self
+                .process_record(location, record)
+                .and_then(|_| self.write_expired(location)),
+            Ok(None) => self.write_expired(location),
+            Err(e) => {
+                file_stats.invalid += 1;
+                metrics::count_parse_error(reason);
+                let earlier_lines = self.nss.take_failed_lines();
+                if let Some(rejects) = &mut self.rejects {
+                    if let Err(f) = rejects.write(location.file_name, location.line_num, reason, &e, raw_line.as_deref()) {
+                        logging::print_with(&f, &location.fields());
+                    }
+
+                    // The other lines of a TLS 1.3 record share the failure of the line that completed it
+                    for line in earlier_lines {
+                        if let Err(f) = rejects.write(&line.file_name, line.line_num, reason, &e, Some(line.line.as_bytes())) {
+                            logging::print_with(&f, &[("file", &line.file_name), ("line", &line.line_num)]);
+                        }
+                    }
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Counts the TLS 1.3 sessions dropped by the NSS assembler as parse errors, appending their lines to the reject file.
+    fn reject_incomplete(&mut self, incomplete: Vec<nss::Incomplete>) -> Result<()> {
+        if incomplete.is_empty() {
+            return Ok(());
+        }
+
+        let count = incomplete.len();
+        for record in incomplete {
+            metrics::count_parse_error(ParseError::Nss);
+            if let Some(first) = record.lines.first() {
+                let index = self.stats_index(&first.file_name);
+                self.stats[index].1.invalid += 1;
+            }
+
+            if let Some(rejects) = &mut self.rejects {
+                let error = anyhow!(
+                    "Incomplete TLS 1.3 NSS record for client random {}",
+                    hex::encode(&record.client_random)
+                );
+                for line in &record.lines {
+                    if let Err(f) = rejects.write(
+                        &line.file_name,
+                        line.line_num,
+                        ParseError::Nss,
+                        &error,
+                        Some(line.line.as_bytes()),
+                    ) {
+                        logging::print_with(&f, &[("file", &line.file_name), ("line", &line.line_num)]);
+                    }
+                }
+            }
+        }
+
+        bail!("Incomplete TLS 1.3 NSS records for {} client randoms", count)
+    }
+
+    fn parse_line<Line: AsRef<str>, Error: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        location: &FileLocation,
+        line: Result<Line, Error>,
+    ) -> Result<Option<Box<dyn TlsRecord>>> {
         let line = line.with_context(|| format!("Failed to read line at {}", location))?;
-        let line = match self.input_format {
+        let line = match self.line_format {
             InputFormat::SslKeylog => InputLine::SslKeylog(line.as_ref()),
-            InputFormat::DdgSyslog => InputLine::DdgSyslog(line.as_ref()),
+            InputFormat::DdgSyslog | InputFormat::Journal => InputLine::DdgSyslog(line.as_ref()),
+            InputFormat::Nss => {
+                return self
+                    .nss
+                    .push(line.as_ref(), location.file_name, location.line_num)
+                    .with_context(|| format!("Failed to parse at {}", location));
+            }
+            InputFormat::Auto => bail!("Undetected input format at {}", location),
         };
-        let record = TlsPre13Record::try_from(&line)
-            .map(|r| Box::from(r) as Box<dyn TlsRecord>)
-            .or_else(|_| Tls13Record::try_from(&line).map(|r| Box::from(r) as Box<dyn TlsRecord>))
-            .with_context(|| format!("Failed to parse at {}", location))?;
+        TlsPre13Record::try_from(&line)
+            .map(|r| Some(Box::from(r) as Box<dyn TlsRecord>))
+            .or_else(|_| Tls13Record::try_from(&line).map(|r| Some(Box::from(r) as Box<dyn TlsRecord>)))
+            .with_context(|| format!("Failed to parse at {}", location))
+    }
+
+    /// Selects the counters of the file whose lines are processed next, looking them up only when the file changes.
+    fn select_file(&mut self, file_name: &str) {
+        if self.current_stats.is_none_or(|i| self.stats[i].0 != file_name) {
+            self.current_stats = Some(self.stats_index(file_name));
+        }
+    }
+
+    fn stats_index(&mut self, file_name: &str) -> usize {
+        match self.stats.iter().rposition(|(n, _)| n == file_name) {
+            Some(i) => i,
+            None => {
+                self.stats.push((String::from(file_name), FileStats::default()));
+                self.stats.len() - 1
+            }
+        }
+    }
+
+    /// Returns the counters of the file selected by [`Self::select_file`].
+    fn file_stats(&mut self) -> &mut FileStats {
+        let index = self.current_stats.expect("No file selected");
+        &mut self.stats[index].1
+    }
+
+    fn process_record(&mut self, location: &FileLocation, record: Box<dyn TlsRecord>) -> Result<()> {
         let metadata = record.get_metadata();
-        if self
+        let is_filtered = self
             .filter
             .map(|f| !f.is_match(&format!("{}:{}", metadata.sni, metadata.server_port)))
-            .unwrap_or(false)
-        {
-            return Ok(None);
+            .unwrap_or(false);
+        let collection_name = CollectionName::new(metadata, metadata.timestamp);
+        let is_dry_run = self.store.is_none();
+        let file_stats = self.file_stats();
+        match record.get_secrets() {
+            TlsSecrets::Pre13 { .. } => file_stats.pre13_records += 1,
+            TlsSecrets::Tls13 { .. } => file_stats.tls13_records += 1,
         }
+        metrics::count_record(matches!(record.get_secrets(), TlsSecrets::Tls13 { .. }));
 
-        let mut document = bson::Document::new();
-        record.serialize(&mut document);
+        if is_filtered {
+            file_stats.filtered += 1;
+            return Ok(());
+        }
 
-        const SUFFIX_FORMAT: &[FormatItem] = format_description!("[year][month][day]");
-        let collection_name = format!(
-            "{}@{}:{}_{}",
-            metadata.sni,
-            metadata.server_ip,
-            metadata.server_port,
-            metadata.timestamp.format(SUFFIX_FORMAT).unwrap()
-        );
-        self.write_document(&collection_name, document, location, batch_map)?;
+        if is_dry_run {
+            file_stats.collections.insert(collection_name.to_string());
+            return Ok(());
+        }
 
         let mut hash = DefaultHasher::new();
-        collection_name.hash(&mut hash);
+        collection_name.to_string().hash(&mut hash);
         let offset = (hash.finish() % 75431) as u32;
         let next_timestamp = metadata.timestamp + Duration::HOUR + Duration::SECOND * offset;
-        let next_collection_name = format!(
-            "{}@{}:{}_{}",
-            metadata.sni,
-            metadata.server_ip,
-            metadata.server_port,
-            next_timestamp.format(SUFFIX_FORMAT).unwrap()
-        );
-        Ok(if next_collection_name != collection_name {
-            Some(next_collection_name)
-        } else {
-            None
-        })
+        let next_collection_name = CollectionName::new(metadata, next_timestamp);
+        if next_collection_name != collection_name {
+            self.next_collection_names.insert(next_collection_name);
+        }
+
+        self.write_record(collection_name, record, location)
     }
 
-    fn write_document(
-        &mut self,
-        collection_name: &str,
-        document: bson::Document,
-        location: &FileLocation,
-        batch_map: &mut HashMap<String, Vec<bson::Document>>,
-    ) -> Result<()> {
-        let batch = batch_map.entry(collection_name.to_string()).or_default();
-        batch.push(document);
-        let len = batch.len();
-        const BATCH_SIZE: usize = 1000;
-        if len >= BATCH_SIZE {
-            println!("{}: writing {} to {}", location.file_name, len, collection_name);
-            let batch = batch_map.remove(collection_name).unwrap();
-            self.store
-                .write(collection_name, batch)
-                .with_context(|| format!("Failed to write to {} for {}", collection_name, location.file_name))?;
+    fn update_pending(&self) {
+        metrics::set_pending(self.batch_map.len(), self.pending_records);
+    }
+
+    fn write_record(&mut self, collection_name: CollectionName, record: Box<dyn TlsRecord>, location: &FileLocation) -> Result<()> {
+        self.record_num += 1;
+        let batch = self.batch_map.entry(collection_name.clone()).or_insert_with(Batch::new);
+        batch.records.push(record);
+        batch.last_record_num = self.record_num;
+        if let Some((source, start)) = self.current {
+            batch
+                .starts
+                .entry(source)
+                .and_modify(|p| *p = (*p).min(start))
+                .or_insert(start);
+        }
+
+        self.pending_records += 1;
+        let result = self.write_over_limits(&collection_name, location);
+        self.update_pending();
+        result
+    }
+
+    /// Writes the batches that exceed the policy limits, the least recently used first.
+    fn write_over_limits(&mut self, collection_name: &CollectionName, location: &FileLocation) -> Result<()> {
+        if self.batch_map[collection_name].records.len() >= self.policy.size {
+            self.write_pending(collection_name, location, "full")?;
+        }
+
+        while self.batch_map.len() > self.policy.max_pending_collections {
+            self.write_least_recent(location, "too many collections")?;
+        }
+
+        while self.pending_records > self.policy.max_pending_records {
+            self.write_least_recent(location, "too many records")?;
+        }
+
+        Ok(())
+    }
+
+    fn write_least_recent(&mut self, location: &FileLocation, reason: &str) -> Result<()> {
+        let collection_name = self
+            .batch_map
+            .iter()
+            .min_by_key(|(_, b)| b.last_record_num)
+            .map(|(n, _)| n.clone())
+            .unwrap();
+        self.write_pending(&collection_name, location, reason)
+    }
+
+    /// Writes the batches older than the maximum age, checking at most once per second.
+    fn write_expired(&mut self, location: &FileLocation) -> Result<()> {
+        const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+        let max_age = match self.policy.max_age {
+            Some(a) if self.last_expiry_check.elapsed() >= CHECK_INTERVAL => a,
+            _ => return Ok(()),
         };
+
+        self.last_expiry_check = Instant::now();
+        let expired: Vec<_> = self
+            .batch_map
+            .iter()
+            .filter(|(_, b)| b.created.elapsed() >= max_age)
+            .map(|(n, _)| n.clone())
+            .collect();
+        for collection_name in expired {
+            self.write_pending(&collection_name, location, "expired")?;
+        }
+
+        self.update_pending();
+        Ok(())
+    }
+
+    /// Writes a pending batch, holding the checkpoints of its sources if it fails.
+    fn write_pending(&mut self, collection_name: &CollectionName, location: &FileLocation, reason: &str) -> Result<()> {
+        let batch = self.batch_map.remove(collection_name).unwrap();
+        self.pending_records -= batch.records.len();
+        logging::log(
+            Level::Debug,
+            &format!(
+                "{}: writing {} to {} ({})",
+                location.file_name,
+                batch.records.len(),
+                collection_name,
+                reason
+            ),
+            &[("file", location.file_name), ("collection", collection_name)],
+        );
+        let store = self.store.as_mut().unwrap();
+        if let Err(e) = write_batch(&mut **store, &mut self.writes, collection_name, &batch.records) {
+            self.hold(&batch.starts);
+
+            return Err(e.context(format!("Failed to write to {} for {}", collection_name, location.file_name)));
+        }
+
         Ok(())
     }
 }
 
+/// Writes a batch to the storage, updating the write counters and metrics.
+fn write_batch(
+    store: &mut dyn Storage,
+    writes: &mut WriteStats,
+    collection_name: &CollectionName,
+    records: &[Box<dyn TlsRecord>],
+) -> Result<()> {
+    let start = Instant::now();
+    let result = store.write(collection_name, records);
+    metrics::observe_write(records.len(), start.elapsed(), result.is_ok());
+    match result {
+        Ok(inserted) => writes.add(collection_name.to_string(), records.len(), inserted),
+        Err(e) => {
+            writes.errors += 1;
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+struct Batch {
+    records: Vec<Box<dyn TlsRecord>>,
+    /// Earliest start of the lines that produced the records, per source.
+    starts: HashMap<usize, Position>,
+    created: Instant,
+    /// Sequence number of the last record added, see `Processor::record_num`.
+    last_record_num: u64,
+}
+
+impl Batch {
+    fn new() -> Self {
+        Self {
+            records: Vec::new(),
+            starts: HashMap::new(),
+            created: Instant::now(),
+            last_record_num: 0,
+        }
+    }
+}
+
+/// An input file whose progress is recorded in the checkpoint state.
+struct Source {
+    name: String,
+    identity: Option<FileIdentity>,
+    position: Position,
+    hold: Option<Position>,
+}
+
 struct FileLocation<'a> {
     pub file_name: &'a dyn std::fmt::Display,
     pub line_num: u64,
 }
 
+impl<'a> FileLocation<'a> {
+    fn fields(&self) -> [logging::Field<'_>; 2] {
+        [("file", self.file_name), ("line", &self.line_num)]
+    }
+}
+
 impl std::fmt::Display for FileLocation<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}:{}", self.file_name, self.line_num))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, str::FromStr, sync::LazyLock};
+
+    use super::*;
+
+    static TERM_TOKEN: LazyLock<Arc<AtomicBool>> = LazyLock::new(Arc::default);
+    static NO_METADATA: LazyLock<nss::Metadata> = LazyLock::new(nss::Metadata::default);
+    static JOURNAL_FILTER: journal::Filter = journal::Filter {
+        identifiers: Vec::new(),
+        units: Vec::new(),
+    };
+
+    /// Keeps the records in memory, failing every write to the collections of `failing_sni`
+    /// and recording the checkpoint of the `observed` file before every write.
+    #[derive(Default)]
+    struct FakeStore {
+        collections: HashMap<CollectionName, HashSet<Vec<u8>>>,
+        batches: Vec<(CollectionName, usize)>,
+        failing_sni: Option<&'static str>,
+        /// State file and input file.
+        observed: Option<(PathBuf, PathBuf)>,
+        checkpoints: Vec<Option<Position>>,
+    }
+
+    impl FakeStore {
+        fn failing(sni: &'static str) -> Self {
+            Self {
+                failing_sni: Some(sni),
+                ..Self::default()
+            }
+        }
+
+        fn observing(state_path: &Path, path: &Path) -> Self {
+            Self {
+                observed: Some((state_path.to_owned(), path.to_owned())),
+                ..Self::default()
+            }
+        }
+    }
+
+    impl Storage for FakeStore {
+        fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize> {
+            if let Some((state_path, path)) = &self.observed {
+                self.checkpoints.push(checkpoint_of(state_path, path));
+            }
+
+            if let Some(sni) = self.failing_sni {
+                anyhow::ensure!(
+                    !collection_name.to_string().starts_with(sni),
+                    "Failed to write to {}",
+                    collection_name
+                );
+            }
+
+            self.batches.push((collection_name.clone(), batch.len()));
+            let collection = self.collections.entry(collection_name.clone()).or_default();
+            Ok(batch
+                .iter()
+                .filter(|r| collection.insert(r.get_metadata().server_random.clone()))
+                .count())
+        }
+
+        fn ensure_collection(&mut self, collection_name: &CollectionName) -> Result<()> {
+            self.collections.entry(collection_name.clone()).or_default();
+            Ok(())
+        }
+
+        fn lookup(&mut self, _: &[Vec<u8>], _: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>> {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Creates a processor of `sslkeylog` lines, or of NSS key log lines with the given metadata.
+    fn test_processor<'a>(
+        store: Option<&'a mut dyn Storage>,
+        state: Option<&'a mut checkpoint::State>,
+        nss_metadata: Option<&'a nss::Metadata>,
+    ) -> Processor<'a> {
+        let input_format = if nss_metadata.is_some() {
+            InputFormat::Nss
+        } else {
+            InputFormat::SslKeylog
+        };
+        Processor::new(
+            None,
+            &TERM_TOKEN,
+            store,
+            input_format,
+            &JOURNAL_FILTER,
+            nss_metadata.unwrap_or(&NO_METADATA),
+            state,
+        )
+    }
+
+    /// A temporary directory that is removed when dropped.
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("sslkeylog-processor-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, name: &str, content: &str) -> PathBuf {
+            let path = self.0.join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn keylog_line(sni: &str, server_random: &str) -> String {
+        format!(
+            "2021-01-02T03:04:05Z 10.0.0.1:50000 10.0.0.2:443 {} 303 {} {} {}\n",
+            sni,
+            server_random.repeat(32),
+            "22".repeat(32),
+            "33".repeat(48)
+        )
+    }
+
+    fn checkpoint_of(state_path: &Path, path: &Path) -> Option<Position> {
+        let identity = FileIdentity::of(&std::fs::metadata(path).unwrap());
+        checkpoint::State::load(state_path)
+            .unwrap()
+            .get(&path.display().to_string(), identity)
+    }
+
+    fn process(processor: &mut Processor, paths: &[&Path]) -> Result<()> {
+        processor.process(paths.iter().map(|p| p.to_str().unwrap()), &completion::Actions::default())
+    }
+
+    #[test]
+    fn processor_writes_records_to_storage() {
+        let directory = TestDirectory::new("processor");
+        let path = directory.write("input.log", &keylog_line("Example.com", "11").repeat(2));
+        let mut store = FakeStore::default();
+        let mut processor = test_processor(Some(&mut store), None, None);
+        process(&mut processor, &[&path]).unwrap();
+        let summary = processor.summary();
+
+        let collection_name = CollectionName::from_str("example.com@10.0.0.2:443_20210102").unwrap();
+        assert_eq!((2, 1), (summary.lines, summary.duplicates));
+        assert_eq!(1, summary.inserted[&collection_name.to_string()]);
+        assert_eq!(1, store.collections[&collection_name].len());
+    }
+
+    #[test]
+    fn least_recently_used_batches_are_written_beyond_limits() {
+        let directory = TestDirectory::new("batches");
+        let lines = [
+            keylog_line("a.com", "11"),
+            keylog_line("b.com", "12"),
+            keylog_line("a.com", "13"),
+            keylog_line("a.com", "14"),
+        ];
+        let path = directory.write("input.log", &lines.concat());
+        let mut store = FakeStore::default();
+        let mut processor = test_processor(Some(&mut store), None, None);
+        processor.limit_batches(BatchPolicy {
+            size: 2,
+            max_pending_collections: 1,
+            ..BatchPolicy::default()
+        });
+        process(&mut processor, &[&path]).unwrap();
+
+        let batches: Vec<_> = store.batches.iter().map(|(n, c)| (n.to_string(), *c)).collect();
+        assert_eq!(
+            vec![
+                (String::from("a.com@10.0.0.2:443_20210102"), 1),
+                (String::from("b.com@10.0.0.2:443_20210102"), 1),
+                (String::from("a.com@10.0.0.2:443_20210102"), 2),
+            ],
+            batches
+        );
+    }
+
+    #[test]
+    fn dry_run_counts_records_per_file() {
+        let directory = TestDirectory::new("dry-run");
+        let path = directory.write("input.log", &format!("{}invalid\n", keylog_line("example.com", "11")));
+        let mut processor = test_processor(None, None, None);
+        let result = process(&mut processor, &[&path]);
+
+        assert!(result.is_err());
+        let (_, stats) = &processor.stats()[0];
+        assert_eq!((1, 0, 1), (stats.pre13_records, stats.tls13_records, stats.invalid));
+        assert!(stats.collections.contains("example.com@10.0.0.2:443_20210102"));
+    }
+
+    #[test]
+    fn failed_flush_per_file_does_not_advance_checkpoint() {
+        let directory = TestDirectory::new("flush");
+        let failing_path = directory.write("a.log", &[keylog_line("a.com", "11"), keylog_line("a.com", "12")].concat());
+        let path = directory.write("b.log", &keylog_line("b.com", "13"));
+        let state_path = directory.0.join("state");
+        let mut state = checkpoint::State::load(&state_path).unwrap();
+        let mut store = FakeStore::failing("a.com");
+        let mut processor = test_processor(Some(&mut store), Some(&mut state), None);
+        processor.limit_batches(BatchPolicy {
+            flush_per_file: true,
+            ..BatchPolicy::default()
+        });
+        let result = process(&mut processor, &[&failing_path, &path]);
+
+        assert!(result.is_err());
+        assert_eq!(Some(Position::default()), checkpoint_of(&state_path, &failing_path));
+        assert_eq!(Some(1), checkpoint_of(&state_path, &path).map(|p| p.line_num));
+        assert_eq!(1, store.batches.len());
+    }
+
+    #[test]
+    fn failed_file_does_not_fail_the_completion_of_the_next_one() {
+        let directory = TestDirectory::new("completion");
+        let failing_path = directory.write("a.log", &keylog_line("a.com", "11"));
+        let path = directory.write("b.log", &keylog_line("b.com", "12"));
+        let quarantine = directory.0.join("quarantine");
+        let mut store = FakeStore::failing("a.com");
+        let mut processor = test_processor(Some(&mut store), None, None);
+        let actions = completion::Actions {
+            on_success: Some(completion::SuccessAction::Delete),
+            quarantine: Some(quarantine.clone()),
+        };
+        let result = processor.process([failing_path.to_str().unwrap(), path.to_str().unwrap()], &actions);
+
+        assert!(result.is_err());
+        assert!(quarantine.join("a.log").is_file());
+        assert!(!path.exists());
+        assert!(!quarantine.join("b.log").exists());
+        assert_eq!(1, store.batches.len());
+    }
+
+    #[test]
+    fn checkpoint_stops_before_pending_and_failed_records() {
+        // A batch size of 1 fails the write of the a.com record, while 2 keeps it pending until the final flush fails
+        for size in [1, 2] {
+            let directory = TestDirectory::new(&format!("checkpoint-{}", size));
+            let lines = [
+                keylog_line("b.com", "11"),
+                keylog_line("a.com", "12"),
+                keylog_line("b.com", "13"),
+                keylog_line("b.com", "14"),
+            ];
+            let path = directory.write("input.log", &lines.concat());
+            let state_path = directory.0.join("state");
+            let mut state = checkpoint::State::load(&state_path).unwrap();
+            let mut store = FakeStore::failing("a.com");
+            let mut processor = test_processor(Some(&mut store), Some(&mut state), None);
+            processor.limit_batches(BatchPolicy {
+                size,
+                ..BatchPolicy::default()
+            });
+            let result = process(&mut processor, &[&path]);
+
+            assert!(result.is_err());
+            assert_eq!(
+                Some(Position {
+                    offset: lines[0].len() as u64,
+                    line_num: 1
+                }),
+                checkpoint_of(&state_path, &path)
+            );
+            assert_eq!(3, store.batches.iter().map(|(_, c)| c).sum::<usize>());
+        }
+    }
+
+    #[test]
+    fn checkpoints_are_saved_per_file_rather_than_per_batch() {
+        let directory = TestDirectory::new("checkpoint-files");
+        let lines = [keylog_line("a.com", "11"), keylog_line("b.com", "12")];
+        let first_path = directory.write("a.log", &lines.concat());
+        let path = directory.write("b.log", &keylog_line("c.com", "13"));
+        let state_path = directory.0.join("state");
+        let mut state = checkpoint::State::load(&state_path).unwrap();
+        let mut store = FakeStore::observing(&state_path, &first_path);
+        let mut processor = test_processor(Some(&mut store), Some(&mut state), None);
+        processor.limit_batches(BatchPolicy {
+            size: 1,
+            ..BatchPolicy::default()
+        });
+        process(&mut processor, &[&first_path, &path]).unwrap();
+
+        let end = Position {
+            offset: lines.concat().len() as u64,
+            line_num: 2,
+        };
+        assert_eq!(vec![None, None, Some(end)], store.checkpoints);
+    }
+
+    fn nss_test_metadata(client_randoms: &[String]) -> nss::Metadata {
+        let mut metadata = nss::Metadata::default();
+        for client_random in client_randoms {
+            metadata
+                .add(&format!(
+                    "2021-01-02T03:04:05Z 10.0.0.1:50000 10.0.0.2:443 example.com {} {}",
+                    client_random, client_random
+                ))
+                .unwrap();
+        }
+
+        metadata
+    }
+
+    #[test]
+    fn incomplete_nss_records_hold_checkpoint() {
+        let directory = TestDirectory::new("nss-hold");
+        let client_randoms = ["11".repeat(32), "12".repeat(32)];
+        let metadata = nss_test_metadata(&client_randoms);
+        let lines = [
+            format!("CLIENT_HANDSHAKE_TRAFFIC_SECRET {} {}\n", client_randoms[0], "cd".repeat(32)),
+            format!("CLIENT_RANDOM {} {}\n", client_randoms[1], "ab".repeat(48)),
+        ];
+        let path = directory.write("input.keylog", &lines.concat());
+        let state_path = directory.0.join("state");
+        let mut state = checkpoint::State::load(&state_path).unwrap();
+        let mut store = FakeStore::default();
+        let mut processor = test_processor(Some(&mut store), Some(&mut state), Some(&metadata));
+        // Lines of a file that is still being followed, flushed before the TLS 1.3 record is complete
+        let source = processor.add_source(&path).unwrap();
+        processor.select_file("input.keylog");
+        let mut start = Position::default();
+        for line in &lines {
+            let end = Position {
+                offset: start.offset + line.len() as u64,
+                line_num: start.line_num + 1,
+            };
+            let location = FileLocation {
+                file_name: &"input.keylog",
+                line_num: end.line_num,
+            };
+            processor
+                .process_source_line(&location, Ok::<_, std::io::Error>(line.trim_end()), Some(source), start, end)
+                .unwrap();
+            start = end;
+        }
+        processor.flush(false).unwrap();
+
+        assert_eq!(1, store.batches.len());
+        assert_eq!(Some(Position::default()), checkpoint_of(&state_path, &path));
+    }
+
+    #[test]
+    fn incomplete_nss_records_are_rejected() {
+        let directory = TestDirectory::new("nss-reject");
+        let client_randoms = ["11".repeat(32), "12".repeat(32)];
+        let metadata = nss_test_metadata(&client_randoms);
+        let lines = [
+            format!("CLIENT_HANDSHAKE_TRAFFIC_SECRET {} {}\n", client_randoms[0], "cd".repeat(32)),
+            format!("CLIENT_RANDOM {} {}\n", client_randoms[1], "ab".repeat(48)),
+        ];
+        let path = directory.write("input.keylog", &lines.concat());
+        let reject_path = directory.0.join("rejects.jsonl");
+        let mut store = FakeStore::default();
+        let mut processor = test_processor(Some(&mut store), None, Some(&metadata));
+        processor.reject_to(reject::RejectFile::open(&reject_path).unwrap());
+        let result = process(&mut processor, &[&path]);
+        let invalid = processor.stats()[0].1.invalid;
+
+        // The incomplete TLS 1.3 record is reported at the end of the file, then dropped
+        assert!(result.is_err());
+        assert_eq!(1, invalid);
+        assert_eq!(1, store.batches.len());
+        let rejects: Vec<serde_json::Value> = std::fs::read_to_string(&reject_path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(1, rejects.len());
+        assert_eq!(
+            ("nss", 1),
+            (
+                rejects[0]["reason"].as_str().unwrap(),
+                rejects[0]["line_num"].as_u64().unwrap()
+            )
+        );
+        assert_eq!(lines[0].trim_end(), rejects[0]["line"]);
+    }
+
+    #[test]
+    fn file_shorter_than_its_checkpoint_is_processed_from_the_start() {
+        let directory = TestDirectory::new("truncated");
+        let path = directory.write("input.log", &keylog_line("example.com", "11"));
+        let state_path = directory.0.join("state");
+        let mut state = checkpoint::State::load(&state_path).unwrap();
+        let identity = FileIdentity::of(&std::fs::metadata(&path).unwrap());
+        state.set(
+            &path.display().to_string(),
+            identity,
+            Position {
+                offset: 4096,
+                line_num: 20,
+            },
+        );
+        let mut store = FakeStore::default();
+        let mut processor = test_processor(Some(&mut store), Some(&mut state), None);
+        process(&mut processor, &[&path]).unwrap();
+
+        assert_eq!(1, store.batches.len());
+        assert_eq!(Some(1), checkpoint_of(&state_path, &path).map(|p| p.line_num));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn spooled_files_are_left_in_place_on_storage_errors() {
+        let directory = TestDirectory::new("spooled");
+        let unstored_path = directory.write("unstored.log", &keylog_line("a.com", "11"));
+        let invalid_path = directory.write("invalid.log", &format!("{}invalid\n", keylog_line("b.com", "12")));
+        let watcher = spool::Watcher::start(&directory.0).unwrap();
+        let mut store = FakeStore::failing("a.com");
+        let mut processor = test_processor(Some(&mut store), None, None);
+        let unstored = processor.process_spooled(&watcher, &unstored_path).unwrap();
+        let invalid = processor.process_spooled(&watcher, &invalid_path).unwrap();
+
+        assert!(!unstored);
+        assert!(invalid);
+        assert!(unstored_path.is_file());
+        assert!(directory.0.join("failed/invalid.log").is_file());
+        assert_eq!(1, store.batches.len());
+    }
+}
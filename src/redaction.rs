@@ -0,0 +1,51 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static SHOW_RAW: AtomicBool = AtomicBool::new(false);
+
+/// Enables raw lines and secrets in diagnostics, which must only be used for debugging.
+pub(crate) fn set_show_raw(value: bool) {
+    SHOW_RAW.store(value, Ordering::Relaxed);
+}
+
+/// Describes a raw input line without disclosing the secrets it contains.
+pub(crate) fn line(value: &str) -> String {
+    if SHOW_RAW.load(Ordering::Relaxed) {
+        return String::from(value);
+    }
+
+    format!("<redacted, length {}, hash {}>", value.len(), short_hash(value))
+}
+
+/// Describes a secret value without disclosing it.
+pub(crate) fn secret(name: &str, value: &str) -> String {
+    if SHOW_RAW.load(Ordering::Relaxed) {
+        return String::from(value);
+    }
+
+    format!("<redacted {}, length {}, hash {}>", name, value.len(), short_hash(value))
+}
+
+fn short_hash(value: &str) -> String {
+    let mut hash = DefaultHasher::new();
+    value.hash(&mut hash);
+    format!("{:08x}", hash.finish() as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secret_is_not_disclosed() {
+        let value = "0123456789abcdef0123456789abcdef";
+        let redacted = secret("premaster", value);
+        assert!(!redacted.contains(value));
+        assert!(redacted.contains("premaster"));
+        assert!(redacted.contains("length 32"));
+        assert_eq!(redacted, secret("premaster", value));
+    }
+}
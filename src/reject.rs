@@ -0,0 +1,128 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde_json::{Map, Value};
+
+use crate::{metrics::ParseError, output};
+
+/// Dead letter file receiving the lines that failed to be parsed, one JSON object per line.
+///
+/// Every object has the `file` and `line_num` of the line, the `reason` code, the `error` message and the raw `line`,
+/// so that the lines can be replayed, e.g. with `jq -r .line`. A line that is not valid UTF-8 has its invalid bytes replaced
+/// in `line`, and its exact bytes base64-encoded in `line_base64`. The file contains secrets and is only accessible by its owner.
+pub(crate) struct RejectFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl RejectFile {
+    /// Opens the file for appending, creating it if needed and restricting it to its owner.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: output::create_private(path, true)?,
+        })
+    }
+
+    /// Appends a rejected line, `line` is missing if it could not be read at all.
+    pub fn write(
+        &mut self,
+        file_name: &dyn Display,
+        line_num: u64,
+        reason: ParseError,
+        error: &anyhow::Error,
+        line: Option<&[u8]>,
+    ) -> Result<()> {
+        let mut object = Map::new();
+        object.insert(String::from("file"), Value::from(file_name.to_string()));
+        object.insert(String::from("line_num"), Value::from(line_num));
+        object.insert(String::from("reason"), Value::from(reason.label()));
+        object.insert(String::from("error"), Value::from(format!("{:#}", error)));
+        object.insert(
+            String::from("line"),
+            line.map_or(Value::Null, |l| Value::from(String::from_utf8_lossy(l))),
+        );
+        if let Some(line) = line.filter(|l| std::str::from_utf8(l).is_err()) {
+            object.insert(
+                String::from("line_base64"),
+                Value::from(base64::engine::general_purpose::STANDARD.encode(line)),
+            );
+        }
+
+        let mut text = Value::Object(object).to_string();
+        text.push('\n');
+        // A single write per line, so that an interrupted run never leaves a partial line before the next one
+        self.file
+            .write_all(text.as_bytes())
+            .with_context(|| format!("Failed to write reject file {}", self.path.display()))
+    }
+}
+
+/// Recovers the bytes of a line that failed to be read because it is not valid UTF-8.
+pub(crate) fn invalid_line(error: &(dyn std::error::Error + 'static)) -> Option<Vec<u8>> {
+    error
+        .downcast_ref::<std::io::Error>()
+        .and_then(|e| e.get_ref())
+        .and_then(|e| e.downcast_ref::<std::string::FromUtf8Error>())
+        .map(|e| e.as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejected_lines_are_appended_as_json() {
+        let path = std::env::temp_dir().join(format!("sslkeylog-processor-reject-{}.jsonl", std::process::id()));
+        let error = anyhow::anyhow!("Invalid line").context("Failed to parse at test.log:2");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::write(&path, b"").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        RejectFile::open(&path)
+            .unwrap()
+            .write(&"test.log", 2, ParseError::Format, &error, Some(b"garbage"))
+            .unwrap();
+        RejectFile::open(&path)
+            .unwrap()
+            .write(&"test.log", 3, ParseError::Read, &error, None)
+            .unwrap();
+        RejectFile::open(&path)
+            .unwrap()
+            .write(&"test.log", 4, ParseError::Read, &error, Some(b"a\xff"))
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            concat!(
+                r#"{"file":"test.log","line_num":2,"reason":"format","error":"Failed to parse at test.log:2: Invalid line","line":"garbage"}"#,
+                "\n",
+                r#"{"file":"test.log","line_num":3,"reason":"read","error":"Failed to parse at test.log:2: Invalid line","line":null}"#,
+                "\n",
+                r#"{"file":"test.log","line_num":4,"reason":"read","error":"Failed to parse at test.log:2: Invalid line","line":"a�","line_base64":"Yf8="}"#,
+                "\n"
+            ),
+            content
+        );
+        #[cfg(unix)]
+        assert_eq!(0o600, mode);
+
+        let bytes = vec![b'a', 0xff];
+        let error = std::io::Error::new(std::io::ErrorKind::InvalidData, String::from_utf8(bytes).unwrap_err());
+        assert_eq!(Some(vec![b'a', 0xff]), invalid_line(&error));
+    }
+}
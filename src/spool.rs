@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::completion;
+
+/// Subdirectory receiving the files that were processed and stored without errors.
+pub(crate) const DONE_DIRECTORY: &str = "done";
+/// Subdirectory receiving the files with lines that failed to be parsed.
+pub(crate) const FAILED_DIRECTORY: &str = "failed";
+
+/// Watches a spool directory for files that are closed after writing or moved into it.
+///
+/// Files whose names start with a dot are ignored, so that writers may create them under a temporary name.
+pub(crate) struct Watcher {
+    directory: PathBuf,
+    #[cfg(target_os = "linux")]
+    inotify: inotify::Inotify,
+}
+
+impl Watcher {
+    /// Starts watching the directory, creating its `done` and `failed` subdirectories if needed.
+    pub fn start(directory: &Path) -> Result<Self> {
+        for name in [DONE_DIRECTORY, FAILED_DIRECTORY] {
+            let path = directory.join(name);
+            std::fs::create_dir_all(&path).with_context(|| format!("Failed to create directory {}", path.display()))?;
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            use inotify::{Inotify, WatchMask};
+
+            let inotify = Inotify::init().context("Failed to initialize inotify")?;
+            inotify
+                .watches()
+                .add(directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+                .with_context(|| format!("Failed to watch directory {}", directory.display()))?;
+            Ok(Self {
+                directory: directory.to_path_buf(),
+                inotify,
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            anyhow::bail!("Watching spool directory {} is only supported on Linux", directory.display())
+        }
+    }
+
+    /// Lists the files already in the directory, sorted by name, e.g. those left over from a previous run.
+    pub fn scan(&self) -> Result<Vec<PathBuf>> {
+        let context = || format!("Failed to read directory {}", self.directory.display());
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.directory).with_context(context)? {
+            let path = entry.with_context(context)?.path();
+            if is_spooled(&path) && path.is_file() {
+                files.push(path);
+            }
+        }
+
+        files.sort();
+        Ok(files)
+    }
+
+    /// Returns the files that arrived since the last call without waiting, or all files if events were lost.
+    #[cfg(target_os = "linux")]
+    pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+        use inotify::EventMask;
+
+        let mut buffer = [0; 4096];
+        let mut files = Vec::new();
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(files),
+                Err(e) => return Err(e).with_context(|| format!("Failed to watch directory {}", self.directory.display())),
+            };
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    crate::logging::print_warning(&format!(
+                        "Missed events of spool directory {}, rescanning",
+                        self.directory.display()
+                    ));
+                    return self.scan();
+                }
+
+                if let Some(name) = event.name.filter(|_| !event.mask.contains(EventMask::ISDIR)) {
+                    let path = self.directory.join(name);
+                    if is_spooled(&path) && !files.contains(&path) {
+                        files.push(path);
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Moves a processed file to the `done` or `failed` subdirectory,
+    /// appending a number to its name if a file with the same name is already there.
+    pub fn complete(&self, path: &Path, is_success: bool) -> Result<PathBuf> {
+        let directory = self
+            .directory
+            .join(if is_success { DONE_DIRECTORY } else { FAILED_DIRECTORY });
+        let name = path.file_name().unwrap_or_default();
+        let mut target = directory.join(name);
+        let mut count = 0;
+        while target.exists() {
+            count += 1;
+            let mut unique_name = name.to_os_string();
+            unique_name.push(format!(".{}", count));
+            target = directory.join(unique_name);
+        }
+
+        completion::rename(path, &target)?;
+        Ok(target)
+    }
+}
+
+fn is_spooled(path: &Path) -> bool {
+    path.file_name().and_then(|n| n.to_str()).is_some_and(|n| !n.starts_with('.'))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arrived_files_are_reported_and_moved() {
+        let directory = std::env::temp_dir().join(format!("sslkeylog-processor-spool-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("old.log"), b"").unwrap();
+        let mut watcher = Watcher::start(&directory).unwrap();
+        std::fs::write(directory.join("new.log"), b"").unwrap();
+        std::fs::write(directory.join(".partial.log"), b"").unwrap();
+        std::fs::write(directory.join("done/new.log"), b"").unwrap();
+
+        let scanned = watcher.scan().unwrap();
+        let arrived = watcher.poll().unwrap();
+        let done = watcher.complete(&directory.join("new.log"), true).unwrap();
+        let failed = watcher.complete(&directory.join("old.log"), false).unwrap();
+        let is_moved = done.is_file() && failed.is_file() && watcher.scan().unwrap().is_empty();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(vec![directory.join("new.log"), directory.join("old.log")], scanned);
+        assert_eq!(vec![directory.join("new.log")], arrived);
+        assert_eq!(directory.join("done/new.log.1"), done);
+        assert_eq!(directory.join("failed/old.log"), failed);
+        assert!(is_moved);
+    }
+}
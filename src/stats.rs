@@ -0,0 +1,179 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use crate::output;
+
+static SNI_WARNINGS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a record whose SNI was replaced with an empty one because it was invalid.
+pub(crate) fn count_sni_warning() {
+    SNI_WARNINGS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn sni_warnings() -> u64 {
+    SNI_WARNINGS.load(Ordering::Relaxed)
+}
+
+/// Counters of a single input file.
+#[derive(Debug, Default)]
+pub(crate) struct FileStats {
+    pub lines: u64,
+    pub pre13_records: u64,
+    pub tls13_records: u64,
+    pub filtered: u64,
+    pub invalid: u64,
+    pub sni_warnings: u64,
+    pub collections: BTreeSet<String>,
+}
+
+impl fmt::Display for FileStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} lines, {} TLS pre-1.3, {} TLS 1.3, {} filtered, {} invalid, {} SNI warnings",
+            self.lines, self.pre13_records, self.tls13_records, self.filtered, self.invalid, self.sni_warnings
+        )?;
+        for collection in &self.collections {
+            write!(f, "\n  {}", collection)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Counters of the batches written to the storage.
+#[derive(Debug, Default)]
+pub(crate) struct WriteStats {
+    pub duplicates: u64,
+    pub inserted: BTreeMap<String, u64>,
+    /// Batches that failed to be written.
+    pub errors: u64,
+}
+
+impl WriteStats {
+    pub fn add(&mut self, collection_name: String, count: usize, inserted: usize) {
+        self.duplicates += count.saturating_sub(inserted) as u64;
+        *self.inserted.entry(collection_name).or_default() += inserted as u64;
+    }
+}
+
+/// Counters of a whole run, summed over the input files.
+#[derive(Debug, Default)]
+pub(crate) struct Summary {
+    pub lines: u64,
+    pub pre13_records: u64,
+    pub tls13_records: u64,
+    pub filtered: u64,
+    pub invalid: u64,
+    pub duplicates: u64,
+    pub inserted: BTreeMap<String, u64>,
+}
+
+impl Summary {
+    pub fn new<'a>(files: impl IntoIterator<Item = &'a FileStats>, writes: &WriteStats) -> Self {
+        let mut summary = Self {
+            duplicates: writes.duplicates,
+            inserted: writes.inserted.clone(),
+            ..Self::default()
+        };
+        for file_stats in files {
+            summary.lines += file_stats.lines;
+            summary.pre13_records += file_stats.pre13_records;
+            summary.tls13_records += file_stats.tls13_records;
+            summary.filtered += file_stats.filtered;
+            summary.invalid += file_stats.invalid;
+        }
+
+        summary
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "lines": self.lines,
+            "records": {
+                "pre13": self.pre13_records,
+                "tls13": self.tls13_records,
+            },
+            "filtered": self.filtered,
+            "invalid": self.invalid,
+            "duplicates": self.duplicates,
+            "inserted": self.inserted,
+        })
+    }
+
+    /// Writes the summary as a JSON object to a file, or to the standard output for [`output::STDOUT_PATH`].
+    pub fn write_report(&self, path: &str) -> Result<()> {
+        let mut output = output::open(path)?;
+        serde_json::to_writer_pretty(&mut output, &self.to_json())
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(output))
+            .and_then(|_| output.flush())
+            .with_context(|| format!("Failed to write report {}", path))
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} lines, {} TLS pre-1.3, {} TLS 1.3, {} filtered, {} invalid, {} duplicates, {} inserted",
+            self.lines,
+            self.pre13_records,
+            self.tls13_records,
+            self.filtered,
+            self.invalid,
+            self.duplicates,
+            self.inserted.values().sum::<u64>()
+        )?;
+        for (collection, inserted) in &self.inserted {
+            write!(f, "\n  {}: {}", collection, inserted)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summary_sums_files_and_writes() {
+        let files = [
+            FileStats {
+                lines: 3,
+                pre13_records: 2,
+                invalid: 1,
+                ..FileStats::default()
+            },
+            FileStats {
+                lines: 2,
+                tls13_records: 1,
+                filtered: 1,
+                ..FileStats::default()
+            },
+        ];
+        let mut writes = WriteStats::default();
+        writes.add(String::from("a@10.0.0.1:443_20210102"), 2, 1);
+        writes.add(String::from("a@10.0.0.1:443_20210102"), 1, 1);
+
+        assert_eq!(
+            json!({
+                "lines": 5,
+                "records": { "pre13": 2, "tls13": 1 },
+                "filtered": 1,
+                "invalid": 1,
+                "duplicates": 1,
+                "inserted": { "a@10.0.0.1:443_20210102": 2 },
+            }),
+            Summary::new(&files, &writes).to_json()
+        );
+    }
+}
@@ -1,62 +1,108 @@
-use std::collections::{
-    hash_map::Entry::{Occupied, Vacant},
-    HashMap,
-};
+mod jsonl;
+mod keylog;
+mod mongo;
+mod postgres;
+mod sqlite;
+
+use anyhow::Result;
 
-use anyhow::{anyhow, Context, Result};
-use mongodb::{
-    bson::{self, doc},
-    sync::{Collection, Database},
+use crate::{
+    configuration::StorageOptions,
+    data_model::{CollectionName, TlsRecord},
 };
 
-use crate::data_model;
+pub(crate) use self::jsonl::BinaryEncoding;
+use self::{jsonl::JsonlStore, keylog::KeylogStore, mongo::MongoStore, postgres::PostgresStore, sqlite::SqliteStore};
+
+/// A storage backend keeping the records in collections (or their equivalent) named after
+/// the server endpoint and the day, see [`CollectionName`].
+pub(crate) trait Storage {
+    /// Stores a batch of records in the collection, ignoring the records that are already stored.
+    /// Returns the number of records that were actually inserted.
+    fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize>;
+
+    /// Prepares the collection ahead of its first write, e.g. creates its indexes.
+    fn ensure_collection(&mut self, collection_name: &CollectionName) -> Result<()>;
 
-pub(crate) struct Store<'a> {
-    db: &'a Database,
-    collections: HashMap<String, Collection<bson::Document>>,
+    /// Finds the records with the specified client randoms in the collections accepted by the filter.
+    fn lookup(&mut self, client_randoms: &[Vec<u8>], filter: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>>;
 }
 
-impl<'a> Store<'a> {
-    pub fn new(db: &'a Database) -> Self {
-        Self {
-            db,
-            collections: HashMap::new(),
-        }
-    }
+pub(crate) fn open(options: &StorageOptions) -> Result<Box<dyn Storage>> {
+    Ok(match options {
+        StorageOptions::Mongo { options, db_name } => Box::new(MongoStore::new(
+            mongodb::sync::Client::with_options(options.as_ref().clone())?.database(db_name),
+        )),
+        StorageOptions::Sqlite { path } => Box::new(SqliteStore::open(path)?),
+        StorageOptions::Postgres { connection_string } => Box::new(PostgresStore::connect(connection_string)?),
+        StorageOptions::Keylog { directory } => Box::new(KeylogStore::new(directory)),
+        StorageOptions::Jsonl { path, encoding } => Box::new(JsonlStore::open(path, *encoding)?),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, str::FromStr};
 
-    pub fn write(&mut self, collection_name: &str, batch: impl IntoIterator<Item = bson::Document>) -> Result<()> {
-        let collection = self.get_collection(collection_name)?;
-        const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
-        match collection.insert_many(batch).ordered(false).run() {
-            Ok(_) => Ok(()),
-            Err(e) => match e.kind.as_ref() {
-                mongodb::error::ErrorKind::InsertMany(mongodb::error::InsertManyError {
-                    write_errors: Some(errors),
-                    ..
-                }) if errors.iter().all(|b| b.code == DUPLICATE_KEY_ERROR_CODE) => Ok(()),
-                _ => Err(anyhow!(e)),
-            },
+    use time::Time;
+
+    use super::*;
+    use crate::{
+        data_model::{RecordMetadata, Tls13Record, TlsPre13Record},
+        nss,
+    };
+
+    /// Builds a record of the collection, a TLS 1.3 one if `is_tls13`.
+    pub(super) fn record(
+        collection_name: &CollectionName,
+        server_random: u8,
+        client_random: u8,
+        is_tls13: bool,
+    ) -> Box<dyn TlsRecord> {
+        let metadata = RecordMetadata {
+            timestamp: collection_name.date.with_time(Time::from_hms(3, 4, 5).unwrap()).assume_utc(),
+            client_ip: IpAddr::from_str("2001:db8::2").unwrap(),
+            server_ip: collection_name.server_ip,
+            server_port: collection_name.server_port,
+            sni: collection_name.sni.clone(),
+            server_random: vec![server_random; 32],
+            client_random: vec![client_random; 32],
+        };
+        if is_tls13 {
+            Box::from(Tls13Record {
+                metadata,
+                server_handshake: vec![0x44; 32],
+                client_handshake: vec![0x55; 32],
+                server_0: vec![0x66; 32],
+                client_0: vec![0x77; 32],
+            })
+        } else {
+            Box::from(TlsPre13Record {
+                metadata,
+                premaster: vec![0x33; 48],
+            })
         }
     }
 
-    pub fn ensure_collection(&mut self, collection_name: &str) {
-        _ = self.get_collection(collection_name)
-    }
+    /// Checks the behavior shared by all backends, starting from an empty storage: duplicates are ignored,
+    /// and records of both TLS versions are found by client random in the accepted collections only.
+    pub(super) fn check_store(store: &mut dyn Storage, collection_name: &CollectionName) {
+        let format = |r: &dyn TlsRecord| format!("{}{}", nss::format_metadata(r.get_metadata()), nss::format_record(r));
+        let written = [record(collection_name, 1, 2, false), record(collection_name, 3, 4, true)];
+        assert_eq!(2, store.write(collection_name, &written).unwrap());
+        assert_eq!(
+            0,
+            store.write(collection_name, &[record(collection_name, 1, 2, false)]).unwrap()
+        );
 
-    fn get_collection(&mut self, collection_name: &str) -> Result<&mut Collection<bson::Document>> {
-        Ok(match self.collections.entry(String::from(collection_name)) {
-            Occupied(e) => e.into_mut(),
-            Vacant(e) => e.insert(create_collection(self.db, collection_name)?),
-        })
+        let mut records = store
+            .lookup(&[vec![2; 32], vec![4; 32], vec![5; 32]], &|n| n == collection_name)
+            .unwrap();
+        records.sort_by(|a, b| a.get_metadata().server_random.cmp(&b.get_metadata().server_random));
+        assert_eq!(
+            written.iter().map(|r| format(r.as_ref())).collect::<Vec<_>>(),
+            records.iter().map(|r| format(r.as_ref())).collect::<Vec<_>>()
+        );
+        assert!(store.lookup(&[vec![2; 32]], &|_| false).unwrap().is_empty());
     }
 }
-
-fn create_collection(db: &Database, name: &str) -> Result<Collection<bson::Document>> {
-    let collection = db.collection(name);
-    let command = doc! {
-        "createIndexes": collection.name(),
-        "indexes": data_model::get_index_model(),
-    };
-    db.run_command(command).run().context("Failed to create indexes")?;
-    Ok(collection)
-}
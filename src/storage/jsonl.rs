@@ -0,0 +1,157 @@
+use std::io::Write;
+
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+use serde_json::{Map, Value};
+
+use super::Storage;
+use crate::{
+    data_model::{CollectionName, TlsRecord, TlsSecrets},
+    output,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum BinaryEncoding {
+    Hex,
+    Base64,
+}
+
+impl TryFrom<&str> for BinaryEncoding {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "hex" => Ok(BinaryEncoding::Hex),
+            "base64" => Ok(BinaryEncoding::Base64),
+            _ => bail!("Unsupported binary encoding {}", value),
+        }
+    }
+}
+
+impl BinaryEncoding {
+    fn encode(self, value: &[u8]) -> Value {
+        Value::from(match self {
+            BinaryEncoding::Hex => hex::encode(value),
+            BinaryEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(value),
+        })
+    }
+}
+
+/// JSON Lines output, writing every record as an object with the same fields as the MongoDB documents,
+/// plus the collection name in the `c` field. Records are appended to an existing file.
+pub(crate) struct JsonlStore {
+    output: Box<dyn Write>,
+    encoding: BinaryEncoding,
+}
+
+impl JsonlStore {
+    pub fn open(path: &str, encoding: BinaryEncoding) -> Result<Self> {
+        Ok(Self {
+            output: output::open_append(path)?,
+            encoding,
+        })
+    }
+
+    fn to_json(&self, collection_name: &CollectionName, record: &dyn TlsRecord) -> Value {
+        let metadata = record.get_metadata();
+        let mut object = Map::new();
+        object.insert(String::from("c"), Value::from(collection_name.to_string()));
+        object.insert(String::from("_id"), self.encoding.encode(&metadata.server_random));
+        object.insert(
+            String::from("t"),
+            Value::from(
+                metadata
+                    .timestamp
+                    .format(&time::format_description::well_known::Rfc3339)
+                    .unwrap_or_default(),
+            ),
+        );
+        object.insert(String::from("r"), self.encoding.encode(&metadata.client_random));
+        object.insert(String::from("i"), Value::from(metadata.client_ip.to_string()));
+        let secrets = match record.get_secrets() {
+            TlsSecrets::Pre13 { premaster } => vec![("k", premaster)],
+            TlsSecrets::Tls13 {
+                server_handshake,
+                client_handshake,
+                server_0,
+                client_0,
+            } => vec![
+                ("h", server_handshake),
+                ("f", client_handshake),
+                ("z", server_0),
+                ("s", client_0),
+            ],
+        };
+        for (name, secret) in secrets {
+            object.insert(String::from(name), self.encoding.encode(secret));
+        }
+
+        Value::Object(object)
+    }
+}
+
+impl Storage for JsonlStore {
+    fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize> {
+        let mut content = Vec::new();
+        for record in batch {
+            serde_json::to_writer(&mut content, &self.to_json(collection_name, record.as_ref()))?;
+            content.push(b'\n');
+        }
+
+        self.output
+            .write_all(&content)
+            .and_then(|_| self.output.flush())
+            .context("Failed to write JSON lines")?;
+        Ok(batch.len())
+    }
+
+    fn ensure_collection(&mut self, _: &CollectionName) -> Result<()> {
+        Ok(())
+    }
+
+    fn lookup(&mut self, _: &[Vec<u8>], _: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>> {
+        bail!("Lookup is not supported by the JSON Lines output")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::IpAddr, str::FromStr};
+
+    use time::OffsetDateTime;
+
+    use super::*;
+    use crate::data_model::{RecordMetadata, TlsPre13Record};
+
+    #[test]
+    fn record_serializes_with_document_field_names() {
+        let store = JsonlStore {
+            output: Box::new(std::io::sink()),
+            encoding: BinaryEncoding::Base64,
+        };
+        let record = TlsPre13Record {
+            metadata: RecordMetadata {
+                timestamp: OffsetDateTime::from_unix_timestamp(1_609_556_645).unwrap(),
+                client_ip: IpAddr::from_str("10.0.0.1").unwrap(),
+                server_ip: IpAddr::from_str("10.0.0.2").unwrap(),
+                server_port: 443,
+                sni: String::from("example.com"),
+                server_random: vec![0xff; 3],
+                client_random: vec![0; 3],
+            },
+            premaster: vec![1, 2, 3],
+        };
+        let collection_name = CollectionName::new(&record.metadata, record.metadata.timestamp);
+        assert_eq!(
+            serde_json::json!({
+                "c": "example.com@10.0.0.2:443_20210102",
+                "_id": "////",
+                "t": "2021-01-02T03:04:05Z",
+                "r": "AAAA",
+                "i": "10.0.0.1",
+                "k": "AQID",
+            }),
+            store.to_json(&collection_name, &record)
+        );
+    }
+}
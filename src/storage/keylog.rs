@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use time::{macros::format_description, Date};
+
+use super::Storage;
+use crate::{
+    data_model::{CollectionName, TlsRecord},
+    logging, nss, output,
+};
+
+const EMPTY_SNI: &str = "_";
+const FILE_EXTENSION: &str = "keylog";
+const DATE_FORMAT: &[time::format_description::FormatItem] = format_description!("[year][month][day]");
+
+/// Flat-file backend, writing NSS key log files that can be loaded by Wireshark.
+///
+/// Every [`CollectionName`] maps to a file `<sni>/<server_ip>_<server_port>/<year><month><day>.keylog`,
+/// with `_` for an empty SNI and `-` in place of IPv6 colons. Every record is preceded by a comment line
+/// with its metadata in the NSS sidecar format, which is also used to skip duplicates.
+/// A new file only appears once its first batch is synced, later batches are appended and synced;
+/// a record that was only partly appended is cut before the next write to the file.
+pub(crate) struct KeylogStore {
+    directory: PathBuf,
+    server_randoms: HashMap<PathBuf, HashSet<Vec<u8>>>,
+}
+
+impl KeylogStore {
+    pub fn new(directory: &str) -> Self {
+        Self {
+            directory: PathBuf::from(directory),
+            server_randoms: HashMap::new(),
+        }
+    }
+
+    fn get_path(&self, collection_name: &CollectionName) -> PathBuf {
+        let sni = if collection_name.sni.is_empty() {
+            EMPTY_SNI
+        } else {
+            &collection_name.sni
+        };
+        let endpoint = format!(
+            "{}_{}",
+            collection_name.server_ip.to_string().replace(':', "-"),
+            collection_name.server_port
+        );
+        let date = collection_name.date.format(DATE_FORMAT).unwrap_or_default();
+        self.directory
+            .join(sni)
+            .join(endpoint)
+            .join(format!("{}.{}", date, FILE_EXTENSION))
+    }
+
+    fn get_server_randoms(&mut self, path: &Path) -> Result<&mut HashSet<Vec<u8>>> {
+        // Keeps memory bounded when following for a long time, the sets are reloaded on demand
+        const MAX_FILES: usize = 1024;
+        if !self.server_randoms.contains_key(path) {
+            if self.server_randoms.len() >= MAX_FILES {
+                self.server_randoms.clear();
+            }
+
+            let server_randoms = read_server_randoms(path)?;
+            self.server_randoms.insert(path.to_path_buf(), server_randoms);
+        }
+
+        Ok(self.server_randoms.get_mut(path).unwrap())
+    }
+}
+
+impl Storage for KeylogStore {
+    fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize> {
+        let path = self.get_path(collection_name);
+        let server_randoms = self.get_server_randoms(&path)?;
+        let mut content = String::new();
+        let mut inserted = 0;
+        for record in batch {
+            let metadata = record.get_metadata();
+            if server_randoms.insert(metadata.server_random.clone()) {
+                content.push_str("# ");
+                content.push_str(&nss::format_metadata(metadata));
+                content.push_str(&nss::format_record(record.as_ref()));
+                inserted += 1;
+            }
+        }
+
+        if content.is_empty() {
+            return Ok(0);
+        }
+
+        let result = if path.exists() {
+            append(&path, content.as_bytes())
+        } else {
+            create(&path, content.as_bytes())
+        };
+        if result.is_err() {
+            // The file state is unknown, so duplicates are detected from the file contents next time
+            self.server_randoms.remove(&path);
+        }
+
+        result.map(|_| inserted)
+    }
+
+    fn ensure_collection(&mut self, collection_name: &CollectionName) -> Result<()> {
+        let path = self.get_path(collection_name);
+        let directory = path.parent().unwrap();
+        std::fs::create_dir_all(directory).with_context(|| format!("Failed to create directory {}", directory.display()))
+    }
+
+    fn lookup(&mut self, client_randoms: &[Vec<u8>], filter: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>> {
+        let client_randoms: HashSet<_> = client_randoms.iter().collect();
+        let mut records = Vec::new();
+        for (path, collection_name) in list_files(&self.directory)? {
+            if !filter(&collection_name) {
+                continue;
+            }
+
+            let file = File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+            let mut metadata = nss::Metadata::default();
+            let mut lines = Vec::new();
+            for (index, line) in BufReader::new(file).lines().enumerate() {
+                let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+                if let Some(entry) = line.strip_prefix("# ") {
+                    metadata
+                        .add(entry)
+                        .with_context(|| format!("Invalid metadata in {}", path.display()))?;
+                } else if line
+                    .split(' ')
+                    .nth(1)
+                    .and_then(|r| hex::decode(r).ok())
+                    .is_some_and(|r| client_randoms.contains(&r))
+                {
+                    lines.push((index as u64 + 1, line));
+                }
+            }
+
+            let mut assembler = nss::Assembler::new(&metadata);
+            for (line_num, line) in lines {
+                if let Some(record) = assembler
+                    .push(&line, &path.display(), line_num)
+                    .with_context(|| format!("Invalid key log line in {}", path.display()))?
+                {
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+/// Reads the server randoms of the complete records of a key log file, if it exists.
+///
+/// A record that was only partly appended at the end of the file, e.g. because of a crash, is cut,
+/// so that it is written again along with the rest of its batch.
+fn read_server_randoms(path: &Path) -> Result<HashSet<Vec<u8>>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    };
+    let mut reader = BufReader::new(file);
+    let mut server_randoms = HashSet::new();
+    // Start offset, server random and secret line count of the record being read
+    let mut record: Option<(u64, Vec<u8>, usize)> = None;
+    let mut partial_start = None;
+    let mut offset = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let count = reader
+            .read_until(b'\n', &mut line)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if count == 0 {
+            break;
+        }
+
+        let start = offset;
+        offset += count as u64;
+        if line.last() != Some(&b'\n') {
+            partial_start = Some(record.as_ref().map_or(start, |r| r.0));
+            break;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        if let Some(entry) = line.strip_prefix("# ") {
+            record = entry
+                .split(' ')
+                .nth(4)
+                .and_then(|r| hex::decode(r).ok())
+                .map(|r| (start, r, 0));
+        } else if let Some((_, _, secret_count)) = &mut record {
+            *secret_count += 1;
+            let record_secret_count = if line.starts_with("CLIENT_RANDOM ") { 1 } else { 4 };
+            if *secret_count >= record_secret_count {
+                server_randoms.insert(record.take().unwrap().1);
+            }
+        }
+    }
+
+    if let Some(length) = partial_start.or(record.map(|r| r.0)) {
+        logging::print_warning(&format!("Cutting partly written record at the end of {}", path.display()));
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_len(length).and_then(|_| f.sync_data()))
+            .with_context(|| format!("Failed to cut {}", path.display()))?;
+    }
+
+    Ok(server_randoms)
+}
+
+fn create(path: &Path, content: &[u8]) -> Result<()> {
+    let directory = path.parent().unwrap();
+    std::fs::create_dir_all(directory).with_context(|| format!("Failed to create directory {}", directory.display()))?;
+    let mut temp_name = path.as_os_str().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+    let mut file = output::create_private(&temp_path, false)?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    std::fs::rename(&temp_path, path).with_context(|| format!("Failed to rename {} to {}", temp_path.display(), path.display()))?;
+    output::sync_directory(directory)
+}
+
+fn append(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = output::create_private(path, true)?;
+    file.write_all(content)
+        .and_then(|_| file.sync_data())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Lists the key log files in the directory tree along with their collection names.
+fn list_files(directory: &Path) -> Result<Vec<(PathBuf, CollectionName)>> {
+    let read_dir = |d: &Path| -> Result<Vec<PathBuf>> {
+        match std::fs::read_dir(d) {
+            Ok(entries) => entries
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()
+                .with_context(|| format!("Failed to list directory {}", d.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e).with_context(|| format!("Failed to list directory {}", d.display())),
+        }
+    };
+    let mut files = Vec::new();
+    for sni_path in read_dir(directory)? {
+        for endpoint_path in read_dir(&sni_path)? {
+            for path in read_dir(&endpoint_path)? {
+                match parse_path(&sni_path, &endpoint_path, &path) {
+                    Some(n) => files.push((path, n)),
+                    None if path.extension().is_some_and(|e| e == FILE_EXTENSION) => {
+                        logging::print_warning(&format!("Unexpected key log file {}", path.display()))
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn parse_path(sni_path: &Path, endpoint_path: &Path, path: &Path) -> Option<CollectionName> {
+    if path.extension()? != FILE_EXTENSION {
+        return None;
+    }
+
+    let sni = sni_path.file_name()?.to_str()?;
+    let (server_ip, server_port) = endpoint_path.file_name()?.to_str()?.rsplit_once('_')?;
+    Some(CollectionName {
+        sni: String::from(if sni == EMPTY_SNI { "" } else { sni }),
+        server_ip: IpAddr::from_str(&server_ip.replace('-', ":")).ok()?,
+        server_port: u16::from_str(server_port).ok()?,
+        date: Date::parse(path.file_stem()?.to_str()?, DATE_FORMAT).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::test::{check_store, record};
+
+    #[test]
+    fn keylog_store_writes_a_file_per_collection() {
+        let directory = std::env::temp_dir().join(format!("sslkeylog-processor-keylog-{}", std::process::id()));
+        let collection_name = CollectionName::from_str("@2001:db8::1:443_20210102").unwrap();
+        let mut store = KeylogStore::new(directory.to_str().unwrap());
+        check_store(&mut store, &collection_name);
+        let content = std::fs::read_to_string(directory.join("_/2001-db8--1_443/20210102.keylog")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(2, content.lines().filter(|l| l.starts_with("# ")).count());
+        assert_eq!(5, content.lines().filter(|l| !l.starts_with("# ")).count());
+    }
+
+    #[test]
+    fn partly_written_records_are_cut_before_writing() {
+        let directory = std::env::temp_dir().join(format!("sslkeylog-processor-keylog-partial-{}", std::process::id()));
+        let collection_name = CollectionName::from_str("example.com@10.0.0.2:443_20210102").unwrap();
+        let path = directory.join("example.com/10.0.0.2_443/20210102.keylog");
+        KeylogStore::new(directory.to_str().unwrap())
+            .write(&collection_name, &[record(&collection_name, 1, 2, true)])
+            .unwrap();
+        let complete = std::fs::read_to_string(&path).unwrap();
+        let record = record(&collection_name, 3, 4, true);
+        let text = format!(
+            "# {}{}",
+            nss::format_metadata(record.get_metadata()),
+            nss::format_record(record.as_ref())
+        );
+        let mut file = output::create_private(&path, true).unwrap();
+        file.write_all(&text.as_bytes()[..text.len() / 2]).unwrap();
+
+        let inserted = KeylogStore::new(directory.to_str().unwrap())
+            .write(&collection_name, &[record])
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(1, inserted);
+        assert_eq!(format!("{}{}", complete, text), content);
+    }
+}
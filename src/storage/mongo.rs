@@ -0,0 +1,104 @@
+use std::{
+    collections::{
+        hash_map::Entry::{Occupied, Vacant},
+        HashMap,
+    },
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use mongodb::{
+    bson::{self, doc},
+    sync::{Collection, Database},
+};
+
+use super::Storage;
+use crate::{
+    data_model::{self, CollectionName, TlsRecord},
+    to_bson::ToBson,
+};
+
+/// MongoDB backend, storing every [`CollectionName`] as a separate collection.
+pub(crate) struct MongoStore {
+    db: Database,
+    collections: HashMap<String, Collection<bson::Document>>,
+}
+
+impl MongoStore {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            collections: HashMap::new(),
+        }
+    }
+
+    fn get_collection(&mut self, collection_name: &str) -> Result<&mut Collection<bson::Document>> {
+        Ok(match self.collections.entry(String::from(collection_name)) {
+            Occupied(e) => e.into_mut(),
+            Vacant(e) => e.insert(create_collection(&self.db, collection_name)?),
+        })
+    }
+}
+
+impl Storage for MongoStore {
+    fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize> {
+        let collection = self.get_collection(&collection_name.to_string())?;
+        let documents = batch.iter().map(|r| {
+            let mut document = bson::Document::new();
+            r.serialize(&mut document);
+            document
+        });
+        const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+        match collection.insert_many(documents).ordered(false).run() {
+            Ok(result) => Ok(result.inserted_ids.len()),
+            Err(e) => match e.kind.as_ref() {
+                mongodb::error::ErrorKind::InsertMany(mongodb::error::InsertManyError {
+                    write_errors: Some(errors),
+                    ..
+                }) if errors.iter().all(|b| b.code == DUPLICATE_KEY_ERROR_CODE) => Ok(batch.len() - errors.len()),
+                _ => Err(anyhow!(e)),
+            },
+        }
+    }
+
+    fn ensure_collection(&mut self, collection_name: &CollectionName) -> Result<()> {
+        self.get_collection(&collection_name.to_string()).map(|_| ())
+    }
+
+    fn lookup(&mut self, client_randoms: &[Vec<u8>], filter: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>> {
+        let names = self.db.list_collection_names().run().context("Failed to list collections")?;
+        let randoms: Vec<_> = client_randoms.iter().map(|r| r.to_bson()).collect();
+        let mut records = Vec::new();
+        for name in names {
+            let collection_name = match CollectionName::from_str(&name) {
+                Ok(n) if filter(&n) => n,
+                _ => continue,
+            };
+            let cursor = self
+                .db
+                .collection::<bson::Document>(&name)
+                .find(doc! { "r": { "$in": randoms.clone() } })
+                .run()
+                .with_context(|| format!("Failed to query {}", name))?;
+            for document in cursor {
+                let document = document.with_context(|| format!("Failed to read from {}", name))?;
+                records.push(
+                    data_model::record_from_document(&document, &collection_name)
+                        .with_context(|| format!("Invalid document in {}", name))?,
+                );
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+fn create_collection(db: &Database, name: &str) -> Result<Collection<bson::Document>> {
+    let collection = db.collection(name);
+    let command = doc! {
+        "createIndexes": collection.name(),
+        "indexes": data_model::get_index_model(),
+    };
+    db.run_command(command).run().context("Failed to create indexes")?;
+    Ok(collection)
+}
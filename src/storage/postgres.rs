@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use postgres::{types::ToSql, Client, NoTls, Row};
+use time::{Date, Duration, OffsetDateTime};
+
+use super::Storage;
+use crate::data_model::{CollectionName, RecordMetadata, Tls13Record, TlsPre13Record, TlsRecord, TlsSecrets};
+
+const TABLE_NAME: &str = "tls_keys";
+
+/// PostgreSQL backend, storing all records in a single table that is partitioned by day,
+/// with the [`CollectionName`] parts as columns.
+pub(crate) struct PostgresStore {
+    client: Client,
+    partitions: HashSet<Date>,
+}
+
+impl PostgresStore {
+    pub fn connect(connection_string: &str) -> Result<Self> {
+        Self::new(Client::connect(connection_string, NoTls).context("Failed to connect to PostgreSQL")?)
+    }
+
+    /// Creates the table if needed, in the first schema of the client's search path.
+    fn new(mut client: Client) -> Result<Self> {
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {0} (
+                    sni TEXT NOT NULL,
+                    server_ip INET NOT NULL,
+                    server_port INTEGER NOT NULL,
+                    date DATE NOT NULL,
+                    _id BYTEA NOT NULL,
+                    t TIMESTAMPTZ NOT NULL,
+                    r BYTEA NOT NULL,
+                    i INET NOT NULL,
+                    k BYTEA,
+                    h BYTEA,
+                    f BYTEA,
+                    z BYTEA,
+                    s BYTEA,
+                    PRIMARY KEY (date, server_ip, server_port, sni, _id)
+                ) PARTITION BY RANGE (date);
+                CREATE INDEX IF NOT EXISTS {0}_random ON {0} (r);
+                CREATE INDEX IF NOT EXISTS {0}_timestamp ON {0} (t);",
+                TABLE_NAME
+            ))
+            .with_context(|| format!("Failed to create table {}", TABLE_NAME))?;
+        Ok(Self {
+            client,
+            partitions: HashSet::new(),
+        })
+    }
+
+    fn create_partition(&mut self, date: Date) -> Result<()> {
+        if self.partitions.contains(&date) {
+            return Ok(());
+        }
+
+        let next_date = date + Duration::DAY;
+        self.client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {0}_{1} PARTITION OF {0} FOR VALUES FROM ('{2}') TO ('{3}')",
+                TABLE_NAME,
+                date.to_string().replace('-', ""),
+                date,
+                next_date
+            ))
+            .with_context(|| format!("Failed to create partition of {} for {}", TABLE_NAME, date))?;
+        self.partitions.insert(date);
+        Ok(())
+    }
+}
+
+impl Storage for PostgresStore {
+    fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize> {
+        const COLUMN_COUNT: usize = 13;
+        // Keeps the parameter count of a statement well below the protocol limit of 65535
+        const MAX_ROWS: usize = 1000;
+        self.create_partition(collection_name.date)?;
+        let server_port = collection_name.server_port as i32;
+        let mut transaction = self.client.transaction().context("Failed to start transaction")?;
+        let mut inserted = 0;
+        for chunk in batch.chunks(MAX_ROWS) {
+            let mut rows = Vec::with_capacity(chunk.len());
+            for record in chunk {
+                let metadata = record.get_metadata();
+                let (k, h, f, z, s) = match record.get_secrets() {
+                    TlsSecrets::Pre13 { premaster } => (Some(premaster), None, None, None, None),
+                    TlsSecrets::Tls13 {
+                        server_handshake,
+                        client_handshake,
+                        server_0,
+                        client_0,
+                    } => (
+                        None,
+                        Some(server_handshake),
+                        Some(client_handshake),
+                        Some(server_0),
+                        Some(client_0),
+                    ),
+                };
+                rows.push((metadata, k, h, f, z, s));
+            }
+
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::with_capacity(rows.len() * COLUMN_COUNT);
+            let mut values = Vec::with_capacity(rows.len());
+            for (metadata, k, h, f, z, s) in &rows {
+                let placeholders: Vec<_> = (params.len() + 1..=params.len() + COLUMN_COUNT)
+                    .map(|i| format!("${}", i))
+                    .collect();
+                values.push(format!("({})", placeholders.join(", ")));
+                params.extend_from_slice(&[
+                    &collection_name.sni,
+                    &collection_name.server_ip,
+                    &server_port,
+                    &collection_name.date,
+                    &metadata.server_random,
+                    &metadata.timestamp,
+                    &metadata.client_random,
+                    &metadata.client_ip,
+                    k,
+                    h,
+                    f,
+                    z,
+                    s,
+                ]);
+            }
+
+            inserted += transaction
+                .execute(
+                    &format!(
+                        "INSERT INTO {} (sni, server_ip, server_port, date, _id, t, r, i, k, h, f, z, s) VALUES {} ON CONFLICT DO NOTHING",
+                        TABLE_NAME,
+                        values.join(", ")
+                    ),
+                    &params,
+                )
+                .with_context(|| format!("Failed to insert into {}", TABLE_NAME))?;
+        }
+
+        transaction.commit().context("Failed to commit transaction")?;
+        Ok(inserted as usize)
+    }
+
+    fn ensure_collection(&mut self, collection_name: &CollectionName) -> Result<()> {
+        self.create_partition(collection_name.date)
+    }
+
+    fn lookup(&mut self, client_randoms: &[Vec<u8>], filter: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>> {
+        let rows = self
+            .client
+            .query(
+                &format!(
+                    "SELECT sni, server_ip, server_port, date, _id, t, r, i, k, h, f, z, s FROM {} WHERE r = ANY($1)",
+                    TABLE_NAME
+                ),
+                &[&client_randoms],
+            )
+            .with_context(|| format!("Failed to query {}", TABLE_NAME))?;
+        let mut records = Vec::new();
+        for row in rows {
+            let collection_name = CollectionName {
+                sni: row.try_get("sni")?,
+                server_ip: row.try_get("server_ip")?,
+                server_port: u16::try_from(row.try_get::<_, i32>("server_port")?).context("Invalid server port")?,
+                date: row.try_get("date")?,
+            };
+            if filter(&collection_name) {
+                records.push(record_from_row(&row, &collection_name).with_context(|| format!("Invalid row in {}", TABLE_NAME))?);
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+fn record_from_row(row: &Row, collection: &CollectionName) -> Result<Box<dyn TlsRecord>> {
+    let metadata = RecordMetadata {
+        timestamp: row.try_get::<_, OffsetDateTime>("t")?,
+        client_ip: row.try_get("i")?,
+        server_ip: collection.server_ip,
+        server_port: collection.server_port,
+        sni: collection.sni.clone(),
+        server_random: row.try_get("_id")?,
+        client_random: row.try_get("r")?,
+    };
+    Ok(match row.try_get::<_, Option<Vec<u8>>>("k")? {
+        Some(premaster) => Box::from(TlsPre13Record { metadata, premaster }),
+        None => Box::from(Tls13Record {
+            metadata,
+            server_handshake: row.try_get("h")?,
+            client_handshake: row.try_get("f")?,
+            server_0: row.try_get("z")?,
+            client_0: row.try_get("s")?,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::storage::test::check_store;
+
+    /// Requires a PostgreSQL database, in which the test creates and drops its own schema, run with
+    /// `SSLKEYLOG_PROCESSOR_TEST_POSTGRES=postgres://postgres@localhost/test cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn postgres_store_keeps_collections_in_daily_partitions() {
+        let connection_string = std::env::var("SSLKEYLOG_PROCESSOR_TEST_POSTGRES")
+            .expect("SSLKEYLOG_PROCESSOR_TEST_POSTGRES must be set to the connection string of a test database");
+        let schema = format!("sslkeylog_processor_test_{}", std::process::id());
+        let mut client = Client::connect(&connection_string, NoTls).unwrap();
+        client
+            .batch_execute(&format!("CREATE SCHEMA {0}; SET search_path TO {0}", schema))
+            .unwrap();
+        let collection_name = CollectionName::from_str("example.com@10.0.0.2:443_20210102").unwrap();
+        let mut store = PostgresStore::new(client).unwrap();
+        check_store(&mut store, &collection_name);
+        let count: i64 = store
+            .client
+            .query_one(&format!("SELECT count(*) FROM {}_20210102", TABLE_NAME), &[])
+            .unwrap()
+            .get(0);
+        store
+            .client
+            .batch_execute(&format!("DROP SCHEMA {} CASCADE", schema))
+            .unwrap();
+
+        assert_eq!(2, count);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, params_from_iter, Connection, Row};
+use time::OffsetDateTime;
+
+use super::Storage;
+use crate::data_model::{CollectionName, RecordMetadata, Tls13Record, TlsPre13Record, TlsRecord, TlsSecrets};
+
+/// SQLite backend, storing every [`CollectionName`] as a separate table with the same columns
+/// as the MongoDB document fields.
+pub(crate) struct SqliteStore {
+    connection: Connection,
+    tables: HashSet<String>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let connection = Connection::open(path).with_context(|| format!("Failed to open SQLite database {}", path))?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .context("Failed to enable write-ahead logging")?;
+        Ok(Self {
+            connection,
+            tables: HashSet::new(),
+        })
+    }
+
+    fn create_table(&mut self, table_name: &str) -> Result<()> {
+        if self.tables.contains(table_name) {
+            return Ok(());
+        }
+
+        let table = quote(table_name);
+        self.connection
+            .execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {0} (
+                    _id BLOB PRIMARY KEY NOT NULL,
+                    t INTEGER NOT NULL,
+                    r BLOB NOT NULL,
+                    i BLOB NOT NULL,
+                    k BLOB,
+                    h BLOB,
+                    f BLOB,
+                    z BLOB,
+                    s BLOB
+                ) WITHOUT ROWID;
+                CREATE INDEX IF NOT EXISTS {1} ON {0} (r);
+                CREATE INDEX IF NOT EXISTS {2} ON {0} (t);",
+                table,
+                quote(&format!("{}/random", table_name)),
+                quote(&format!("{}/timestamp", table_name)),
+            ))
+            .with_context(|| format!("Failed to create table {}", table_name))?;
+        self.tables.insert(String::from(table_name));
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStore {
+    fn write(&mut self, collection_name: &CollectionName, batch: &[Box<dyn TlsRecord>]) -> Result<usize> {
+        let table_name = collection_name.to_string();
+        self.create_table(&table_name)?;
+        let transaction = self.connection.transaction().context("Failed to start transaction")?;
+        let mut inserted = 0;
+        {
+            let mut statement = transaction.prepare_cached(&format!(
+                "INSERT OR IGNORE INTO {} (_id, t, r, i, k, h, f, z, s) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                quote(&table_name)
+            ))?;
+            for record in batch {
+                let metadata = record.get_metadata();
+                let timestamp = (metadata.timestamp.unix_timestamp_nanos() / 1_000_000) as i64;
+                let client_ip = match metadata.client_ip {
+                    IpAddr::V4(a) => a.octets().to_vec(),
+                    IpAddr::V6(a) => a.octets().to_vec(),
+                };
+                let (k, h, f, z, s) = match record.get_secrets() {
+                    TlsSecrets::Pre13 { premaster } => (Some(premaster), None, None, None, None),
+                    TlsSecrets::Tls13 {
+                        server_handshake,
+                        client_handshake,
+                        server_0,
+                        client_0,
+                    } => (
+                        None,
+                        Some(server_handshake),
+                        Some(client_handshake),
+                        Some(server_0),
+                        Some(client_0),
+                    ),
+                };
+                inserted += statement.execute(params![
+                    metadata.server_random,
+                    timestamp,
+                    metadata.client_random,
+                    client_ip,
+                    k,
+                    h,
+                    f,
+                    z,
+                    s
+                ])?;
+            }
+        }
+
+        transaction.commit().context("Failed to commit transaction")?;
+        Ok(inserted)
+    }
+
+    fn ensure_collection(&mut self, collection_name: &CollectionName) -> Result<()> {
+        self.create_table(&collection_name.to_string())
+    }
+
+    fn lookup(&mut self, client_randoms: &[Vec<u8>], filter: &dyn Fn(&CollectionName) -> bool) -> Result<Vec<Box<dyn TlsRecord>>> {
+        let names = self
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .and_then(|mut s| {
+                s.query_map([], |r| r.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .context("Failed to list tables")?;
+        // Queried in chunks, since the number of parameters of a statement is limited (999 before SQLite 3.32)
+        const MAX_PARAMETERS: usize = 999;
+        let mut records = Vec::new();
+        for name in names {
+            let collection_name = match CollectionName::from_str(&name) {
+                Ok(n) if filter(&n) => n,
+                _ => continue,
+            };
+            for chunk in client_randoms.chunks(MAX_PARAMETERS) {
+                let mut statement = self
+                    .connection
+                    .prepare_cached(&format!(
+                        "SELECT _id, t, r, i, k, h, f, z, s FROM {} WHERE r IN ({})",
+                        quote(&name),
+                        vec!["?"; chunk.len()].join(", ")
+                    ))
+                    .with_context(|| format!("Failed to query {}", name))?;
+                let mut rows = statement
+                    .query(params_from_iter(chunk))
+                    .with_context(|| format!("Failed to query {}", name))?;
+                while let Some(row) = rows.next().with_context(|| format!("Failed to read from {}", name))? {
+                    records.push(record_from_row(row, &collection_name).with_context(|| format!("Invalid row in {}", name))?);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+fn record_from_row(row: &Row, collection: &CollectionName) -> Result<Box<dyn TlsRecord>> {
+    let client_ip = match row.get::<_, Vec<u8>>("i")?.as_slice() {
+        &[a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        octets => match <[u8; 16]>::try_from(octets) {
+            Ok(octets) => IpAddr::V6(Ipv6Addr::from(octets)),
+            Err(_) => bail!("Invalid client IP address length {}", octets.len()),
+        },
+    };
+    let metadata = RecordMetadata {
+        timestamp: OffsetDateTime::from_unix_timestamp_nanos(row.get::<_, i64>("t")? as i128 * 1_000_000)
+            .context("Invalid timestamp")?,
+        client_ip,
+        server_ip: collection.server_ip,
+        server_port: collection.server_port,
+        sni: collection.sni.clone(),
+        server_random: row.get("_id")?,
+        client_random: row.get("r")?,
+    };
+    Ok(match row.get::<_, Option<Vec<u8>>>("k")? {
+        Some(premaster) => Box::from(TlsPre13Record { metadata, premaster }),
+        None => Box::from(Tls13Record {
+            metadata,
+            server_handshake: row.get("h")?,
+            client_handshake: row.get("f")?,
+            server_0: row.get("z")?,
+            client_0: row.get("s")?,
+        }),
+    })
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::test::check_store;
+
+    #[test]
+    fn sqlite_store_keeps_collections_in_tables() {
+        let path = std::env::temp_dir().join(format!("sslkeylog-processor-{}.sqlite", std::process::id()));
+        let collection_name = CollectionName::from_str("example.com@10.0.0.2:443_20210102").unwrap();
+        let mut store = SqliteStore::open(path.to_str().unwrap()).unwrap();
+        check_store(&mut store, &collection_name);
+        // More client randoms than the parameters of a statement
+        let mut client_randoms: Vec<_> = (0..40000u32).map(|n| n.to_be_bytes().to_vec()).collect();
+        client_randoms.push(vec![4; 32]);
+        let found = store.lookup(&client_randoms, &|_| true).unwrap().len();
+        let journal_mode: String = store
+            .connection
+            .pragma_query_value(None, "journal_mode", |r| r.get(0))
+            .unwrap();
+        let count: i64 = store
+            .connection
+            .query_row(
+                &format!("SELECT count(*) FROM {}", quote(&collection_name.to_string())),
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+
+        assert_eq!("wal", journal_mode);
+        assert_eq!(2, count);
+        assert_eq!(1, found);
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{data_model, logging};
+
+/// Longest accepted syslog message, larger TCP frames close the connection.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// Number of received messages that may wait for processing before the receivers block.
+const QUEUE_SIZE: usize = 10_000;
+/// Number of TCP connections that may be open at the same time, further connections are closed when accepted.
+const MAX_CONNECTIONS: usize = 256;
+/// Time without receiving any data after which a TCP connection is closed.
+const READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub(crate) struct Options {
+    pub udp: Option<SocketAddr>,
+    pub tcp: Option<SocketAddr>,
+    /// Source networks that may send messages, only the loopback addresses if empty.
+    pub allowed: Vec<Network>,
+}
+
+/// An IP network in CIDR notation, or a single address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Network {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let address = IpAddr::from_str(address).with_context(|| format!("Invalid network address {}", s))?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => u8::from_str(p)
+                .ok()
+                .filter(|p| *p <= max_len)
+                .ok_or_else(|| anyhow!("Invalid network prefix length {}", s))?,
+            None => max_len,
+        };
+        Ok(Self { address, prefix_len })
+    }
+}
+
+impl Network {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(a) => a.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            a => a,
+        };
+        match (self.address, address) {
+            (IpAddr::V4(n), IpAddr::V4(a)) => prefix_matches(&n.octets(), &a.octets(), self.prefix_len),
+            (IpAddr::V6(n), IpAddr::V6(a)) => prefix_matches(&n.octets(), &a.octets(), self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], address: &[u8], prefix_len: u8) -> bool {
+    let prefix_len = prefix_len as usize;
+    let (bytes, bits) = (prefix_len / 8, prefix_len % 8);
+    network[..bytes] == address[..bytes] && (bits == 0 || (network[bytes] ^ address[bytes]) >> (8 - bits) == 0)
+}
+
+/// A received syslog message, with its payload converted to a DDG syslog line.
+pub(crate) struct Message {
+    pub peer: SocketAddr,
+    pub line: std::io::Result<String>,
+}
+
+/// Receives syslog messages on UDP and/or TCP from background threads.
+pub(crate) struct Listener {
+    receiver: Receiver<Message>,
+}
+
+impl Listener {
+    pub fn start(options: &Options) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let allowed = Arc::new(options.allowed.clone());
+        if let Some(address) = options.udp {
+            let socket = UdpSocket::bind(address).with_context(|| format!("Failed to listen on UDP {}", address))?;
+            let (sender, allowed) = (sender.clone(), Arc::clone(&allowed));
+            std::thread::spawn(move || receive_udp(socket, &sender, &allowed));
+        }
+
+        if let Some(address) = options.tcp {
+            let listener = TcpListener::bind(address).with_context(|| format!("Failed to listen on TCP {}", address))?;
+            let (sender, allowed) = (sender.clone(), Arc::clone(&allowed));
+            std::thread::spawn(move || accept_tcp(listener, &sender, &allowed));
+        }
+
+        Ok(Self { receiver })
+    }
+
+    /// Waits for the next message, returning `None` on timeout.
+    pub fn receive(&self, timeout: Duration) -> Result<Option<Message>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(m) => Ok(Some(m)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("Syslog receivers stopped"),
+        }
+    }
+
+    /// Returns a message that was already received, without waiting.
+    pub fn try_receive(&self) -> Option<Message> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+fn is_allowed(allowed: &[Network], address: IpAddr) -> bool {
+    if allowed.is_empty() {
+        return address.is_loopback() || matches!(address, IpAddr::V6(a) if a.to_ipv4_mapped().is_some_and(|a| a.is_loopback()));
+    }
+
+    allowed.iter().any(|n| n.contains(address))
+}
+
+fn receive_udp(socket: UdpSocket, sender: &SyncSender<Message>, allowed: &[Network]) {
+    let mut buffer = vec![0; MAX_MESSAGE_SIZE];
+    loop {
+        let (count, peer) = match socket.recv_from(&mut buffer) {
+            Ok(r) => r,
+            Err(e) => {
+                logging::log_error(
+                    logging::Level::Warn,
+                    &anyhow!(e).context("Failed to receive syslog datagram"),
+                    &[],
+                );
+                continue;
+            }
+        };
+        if !is_allowed(allowed, peer.ip()) {
+            logging::log(logging::Level::Debug, &format!("Ignoring syslog datagram from {}", peer), &[]);
+            continue;
+        }
+
+        let line = parse(&buffer[..count], OffsetDateTime::now_utc());
+        if sender.send(Message { peer, line }).is_err() {
+            return;
+        }
+    }
+}
+
+/// Decrements the count of open connections when a connection is closed.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn accept_tcp(listener: TcpListener, sender: &SyncSender<Message>, allowed: &[Network]) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                logging::log_error(
+                    logging::Level::Warn,
+                    &anyhow!(e).context("Failed to accept syslog connection"),
+                    &[],
+                );
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(p) if is_allowed(allowed, p.ip()) => p,
+            Ok(p) => {
+                logging::print_warning(&format!("Rejecting syslog connection from {}", p));
+                continue;
+            }
+            Err(_) => continue,
+        };
+        if connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::Relaxed);
+            logging::print_warning(&format!(
+                "Rejecting syslog connection from {}, {} connections are already open",
+                peer, MAX_CONNECTIONS
+            ));
+            continue;
+        }
+
+        let guard = ConnectionGuard(Arc::clone(&connections));
+        if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            logging::log_error(
+                logging::Level::Warn,
+                &anyhow!(e).context(format!("Failed to set the timeout of syslog connection from {}", peer)),
+                &[],
+            );
+            continue;
+        }
+
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            let _guard = guard;
+            match receive_tcp(stream, peer, &sender) {
+                Ok(()) => {}
+                Err(e) if is_timeout(&e) => {
+                    logging::log(
+                        logging::Level::Debug,
+                        &format!("Closing idle syslog connection from {}", peer),
+                        &[],
+                    );
+                }
+                Err(e) => logging::log_error(
+                    logging::Level::Warn,
+                    &e.context(format!("Closing syslog connection from {}", peer)),
+                    &[],
+                ),
+            }
+        });
+    }
+}
+
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut))
+}
+
+/// Reads the messages of a TCP connection, with either octet-counting (RFC 6587) or newline framing,
+/// which is detected for every message.
+fn receive_tcp(stream: TcpStream, peer: SocketAddr, sender: &SyncSender<Message>) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let first = match reader.fill_buf()?.first() {
+            Some(b) => *b,
+            None => return Ok(()),
+        };
+        let mut frame = Vec::new();
+        if first.is_ascii_digit() {
+            (&mut reader).take(8).read_until(b' ', &mut frame)?;
+            let length = std::str::from_utf8(&frame)
+                .ok()
+                .and_then(|l| l.strip_suffix(' '))
+                .and_then(|l| usize::from_str(l).ok())
+                .filter(|l| *l <= MAX_MESSAGE_SIZE)
+                .context("Invalid syslog frame length")?;
+            frame = vec![0; length];
+            reader.read_exact(&mut frame)?;
+        } else {
+            (&mut reader)
+                .take(MAX_MESSAGE_SIZE as u64 + 1)
+                .read_until(b'\n', &mut frame)?;
+            if frame.last() == Some(&b'\n') {
+                frame.pop();
+            } else if frame.len() > MAX_MESSAGE_SIZE {
+                bail!("Syslog message is too long");
+            }
+        }
+
+        let line = parse(&frame, OffsetDateTime::now_utc());
+        if sender.send(Message { peer, line }).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Extracts the message of a RFC 5424 or RFC 3164 syslog frame as a DDG syslog line,
+/// prefixed with the header timestamp (or the reception time) if it lacks its own.
+pub(crate) fn parse(frame: &[u8], received: OffsetDateTime) -> std::io::Result<String> {
+    let frame = std::str::from_utf8(frame).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let frame = frame.trim_end_matches(['\r', '\n', '\0']);
+    let (timestamp, message) =
+        parse_header(frame).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid syslog header"))?;
+    if data_model::starts_with_timestamp(message) {
+        return Ok(String::from(message));
+    }
+
+    Ok(data_model::prefix_timestamp(message, timestamp.unwrap_or(received)))
+}
+
+fn parse_header(frame: &str) -> Option<(Option<OffsetDateTime>, &str)> {
+    let rest = frame.strip_prefix('<')?;
+    let (priority, rest) = rest.split_once('>')?;
+    if priority.is_empty() || priority.len() > 3 || !priority.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    if let Some(rest) = rest.strip_prefix("1 ") {
+        // RFC 5424: TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]
+        let mut parts = rest.splitn(6, ' ');
+        let timestamp = OffsetDateTime::parse(parts.next()?, &Rfc3339).ok();
+        for _ in 0..4 {
+            parts.next()?;
+        }
+
+        let message = skip_structured_data(parts.next()?)?;
+        let message = message.strip_prefix(' ').unwrap_or(message);
+        return Some((timestamp, message.strip_prefix('\u{feff}').unwrap_or(message)));
+    }
+
+    // RFC 3164: TIMESTAMP HOSTNAME TAG: MSG, where the timestamp lacks the year and the time zone,
+    // unless the sender uses RFC 3339 timestamps
+    let rfc3339 = rest
+        .split_once(' ')
+        .and_then(|(t, r)| Some((OffsetDateTime::parse(t, &Rfc3339).ok()?, r)));
+    let (timestamp, rest) = match rfc3339 {
+        Some((t, r)) => (Some(t), r),
+        None if rest.as_bytes().get(15) == Some(&b' ') => (None, rest.get(16..)?),
+        None => return None,
+    };
+    let (_hostname, rest) = rest.split_once(' ')?;
+    let message = match rest.split_once(": ") {
+        Some((tag, message)) if !tag.contains(' ') => message,
+        _ => rest,
+    };
+    Some((timestamp, message))
+}
+
+/// Skips the structured data elements (`-` or `[id param="value"...]...`), returning the rest.
+fn skip_structured_data(value: &str) -> Option<&str> {
+    if let Some(rest) = value.strip_prefix('-') {
+        return Some(rest);
+    }
+
+    let mut chars = value.char_indices();
+    let mut is_in_element = false;
+    let mut is_in_value = false;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if is_in_value => {
+                chars.next();
+            }
+            '"' if is_in_element => is_in_value = !is_in_value,
+            '[' if !is_in_element => is_in_element = true,
+            ']' if is_in_element && !is_in_value => is_in_element = false,
+            _ if !is_in_element => return Some(&value[i..]),
+            _ => {}
+        }
+    }
+
+    (!is_in_element).then_some("")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_extracts_messages() {
+        let received = OffsetDateTime::from_unix_timestamp(1_609_556_645).unwrap();
+        let parse = |frame: &str| parse(frame.as_bytes(), received).unwrap();
+        assert_eq!(
+            "2021-01-01T00:00:00Z example.com",
+            parse(r#"<134>1 2021-01-01T01:00:00+01:00 host app 1 - [a b="c\"]"][d] example.com"#)
+        );
+        assert_eq!(
+            "2021-01-02T03:04:05Z example.com",
+            parse("<134>1 - host app - - - \u{feff}example.com")
+        );
+        assert_eq!(
+            "2021-01-02T03:04:05Z example.com",
+            parse("<134>Jan  2 03:04:05 host app[12]: example.com")
+        );
+        assert_eq!(
+            "2021-01-01T00:00:00Z example.com",
+            parse("<134>Jan  2 03:04:05 host app: 2021-01-01T00:00:00Z example.com")
+        );
+        assert!(super::parse(b"no header", received).is_err());
+    }
+
+    #[test]
+    fn networks_contain_addresses() {
+        let network = Network::from_str("10.1.0.0/15").unwrap();
+        assert!(network.contains(IpAddr::from_str("10.0.255.1").unwrap()));
+        assert!(network.contains(IpAddr::from_str("::ffff:10.1.0.1").unwrap()));
+        assert!(!network.contains(IpAddr::from_str("10.2.0.1").unwrap()));
+        assert!(Network::from_str("2001:db8::/32")
+            .unwrap()
+            .contains(IpAddr::from_str("2001:db8:1::1").unwrap()));
+        assert!(Network::from_str("10.0.0.0/33").is_err());
+        assert!(is_allowed(&[], IpAddr::from_str("127.0.0.1").unwrap()));
+        assert!(!is_allowed(&[], IpAddr::from_str("10.0.0.1").unwrap()));
+    }
+}
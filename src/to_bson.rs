@@ -1,5 +1,6 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+use anyhow::{bail, Result};
 use mongodb::bson::{self, Bson};
 
 pub(crate) trait ToBson {
@@ -26,3 +27,29 @@ fn to_bson(&self) -> Bson {
         }
     }
 }
+
+pub(crate) trait FromBson: Sized {
+    fn from_bson(value: &Bson) -> Result<Self>;
+}
+
+impl FromBson for Vec<u8> {
+    fn from_bson(value: &Bson) -> Result<Self> {
+        match value {
+            Bson::Binary(b) => Ok(b.bytes.clone()),
+            _ => bail!("Expected binary, got {:?}", value.element_type()),
+        }
+    }
+}
+
+impl FromBson for IpAddr {
+    fn from_bson(value: &Bson) -> Result<Self> {
+        match value {
+            Bson::Int32(a) => Ok(IpAddr::V4(Ipv4Addr::from(*a as u32))),
+            Bson::Binary(b) => match <[u8; 16]>::try_from(b.bytes.as_slice()) {
+                Ok(octets) => Ok(IpAddr::V6(Ipv6Addr::from(octets))),
+                Err(_) => bail!("Invalid IPv6 address length {}", b.bytes.len()),
+            },
+            _ => bail!("Expected int or binary, got {:?}", value.element_type()),
+        }
+    }
+}